@@ -1,261 +1,459 @@
 use std::{
-    collections::HashSet,
+    collections::HashMap,
+    io::Write,
     num::NonZero,
-    ops::{Deref, DerefMut},
-    path::{self, Path, PathBuf},
-    str::FromStr,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
 };
 
-use clap::Parser;
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum, error::ErrorKind};
 use crossbeam::channel::{Receiver, Sender};
-use gas::io::v1::{GasFileReader, GasFileWriter, get_bincode_cfg};
-use gskits::{
-    gsbam::bam_record_ext::BamRecordExt,
-    pbar::{DEFAULT_INTERVAL, get_spin_pb},
+use gas::{
+    fastx::{FastxFormat, FastxReader, create_fastx_writer, write_fastx_record},
+    filter::StatFilter,
+    io::{
+        stream::{GasStreamReader, GasStreamWriter},
+        v1::{GasFileReader, GasFileWriter, GasRecord, get_bincode_cfg},
+    },
+    reads::{
+        BAM_HEADER_SECTION, BatchReads, READS_FORMAT_SECTION, ReadFilter, ReadInfo,
+        TAG_SELECTION_SECTION, TYPED_TAGS, TagSelection, ZmwGroups, check_reads_format,
+        read_name_list, reads_format_section,
+    },
 };
-use rust_htslib::bam::{self, Read, Record, record::Aux};
+use gskits::pbar::{DEFAULT_INTERVAL, get_spin_pb};
+use rust_htslib::bam::{self, Read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// all subreads of a zmw in one record
+    Zmw,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TagPreset {
+    /// every tag
+    All,
+    /// ccs read tags and the kinetics
+    CcsKinetics,
+    /// no tags, only name/seq/qual and the alignment
+    SeqOnly,
+}
+
+/// ccs-kinetics adds these to the typed tags of ReadInfo
+const CCS_KINETICS_EXTRA_TAGS: [&str; 8] = ["RG", "zm", "fi", "fp", "fn", "ri", "rp", "rn"];
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about = "convert between bam and gas", long_about = None)]
 pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// bam to gas. `-` as out path writes a gas stream to stdout
+    B2g(B2gArgs),
+    /// gas to bam. `-` as in path reads a gas stream from stdin
+    G2b(G2bArgs),
+    /// fasta/fastq, plain or gzipped, to gas. `-` as out path writes a gas stream to stdout
+    Fastx2gas(Fastx2gasArgs),
+    /// gas to fasta/fastq, gzipped if the out path ends with .gz. `-` as in path reads a gas stream from stdin
+    Gas2fastx(Gas2fastxArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct B2gArgs {
     pub in_path: String,
-    
+
     pub out_path: String,
 
-    #[arg(long="mode", default_value_t=String::from_str("b2g").unwrap(), help="b2g/g2b, bam2gas or gas2bam")]
-    pub mode: String,
-    #[arg(long = "in-threads", default_value_t = 4)]
-    pub in_threads: usize,
+    #[arg(long = "in-threads", default_value = "4")]
+    pub in_threads: NonZero<usize>,
+
+    #[arg(long = "codec-threads", default_value = "4")]
+    pub codec_threads: NonZero<usize>,
+
+    #[arg(long = "o-threads", default_value = "1")]
+    pub writer_threads: NonZero<usize>,
+
+    #[arg(
+        long = "rep-times",
+        help = "send the first read N times, for benchmarking"
+    )]
+    pub rep_times: Option<NonZero<usize>>,
 
-    #[arg(long = "codec-threads", default_value_t = 4)]
-    pub codec_threads: usize,
+    #[arg(
+        long = "batch-size",
+        default_value = "1",
+        help = "number of reads per record"
+    )]
+    pub batch_size: NonZero<usize>,
 
-    #[arg(long = "o-threads", default_value_t = 1)]
-    pub writer_threads: usize,
+    #[arg(
+        long = "group-by",
+        value_enum,
+        conflicts_with_all = ["batch_size", "rep_times"],
+        help = "one record per group instead of --batch-size reads. zmw: by movie and ch/zm tag \
+                or movie/zmw/start_end name, the input must be in subread bam order"
+    )]
+    pub group_by: Option<GroupBy>,
 
-    #[arg(long = "rep-times", help = "only valid for b2g")]
-    pub rep_times: Option<usize>,
+    #[arg(
+        long = "key",
+        value_parser = ["name", "ch"],
+        help = "index the records by read name or zmw hole number so they can be looked up by key"
+    )]
+    pub key: Option<String>,
 
-    #[arg(long = "batch-size", help = "only valid for b2g")]
-    pub batch_size: Option<usize>,
+    #[arg(
+        long = "preset",
+        value_enum,
+        help = "the tags to start from. default: all, or none if --keep-tags is given"
+    )]
+    pub preset: Option<TagPreset>,
+
+    #[arg(
+        long = "keep-tags",
+        value_delimiter = ',',
+        help = "e.g. dw,ar. tags to keep in addition to the preset"
+    )]
+    pub keep_tags: Vec<String>,
+
+    #[arg(
+        long = "drop-tags",
+        value_delimiter = ',',
+        help = "e.g. dw,ar. tags to drop, wins over --keep-tags and the preset"
+    )]
+    pub drop_tags: Vec<String>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
 }
 
-impl Cli {
-    fn get_out_path(&self) -> PathBuf {
-        path::Path::new(&self.out_path).into()
+impl B2gArgs {
+    fn tag_selection(&self) -> TagSelection {
+        let preset = self.preset.unwrap_or(if self.keep_tags.is_empty() {
+            TagPreset::All
+        } else {
+            TagPreset::SeqOnly
+        });
+        let tags = match preset {
+            TagPreset::All => TagSelection::all(),
+            TagPreset::CcsKinetics => TagSelection::none()
+                .with_tags(TYPED_TAGS)
+                .with_tags(CCS_KINETICS_EXTRA_TAGS),
+            TagPreset::SeqOnly => TagSelection::none(),
+        };
+        tags.with_tags(&self.keep_tags)
+            .without_tags(&self.drop_tags)
     }
 }
 
-#[derive(Debug, Default, bincode::Encode, bincode::Decode)]
-pub struct ReadInfo {
-    pub name: String,
-    pub seq: String,
-    pub cx: Option<u8>,
-    pub ch: Option<u32>,
-    pub np: Option<u32>,
-    pub rq: Option<f32>,
-    pub qual: Option<Vec<u8>>, // phreq, no offset
-    pub dw: Option<Vec<u8>>,
-    pub ar: Option<Vec<u8>>,
-    pub cr: Option<Vec<u8>>,
-    pub be: Option<Vec<u32>>,
-    pub nn: Option<Vec<u8>>,
-    pub wd: Option<Vec<u8>>, // linker width
-    pub sd: Option<Vec<u8>>, // standard devition
-    pub sp: Option<Vec<u8>>, // slope
-}
-
-impl ReadInfo {
-    pub fn new_fa_record(name: String, seq: String) -> Self {
-        let mut res = Self::default();
-        res.name = name;
-        res.seq = seq;
-        res
+#[derive(Args, Debug)]
+pub struct G2bArgs {
+    pub in_path: String,
+
+    pub out_path: String,
+
+    #[arg(long = "in-threads", default_value = "4")]
+    pub in_threads: NonZero<usize>,
+
+    #[arg(long = "codec-threads", default_value = "4")]
+    pub codec_threads: NonZero<usize>,
+
+    #[arg(
+        long = "o-threads",
+        default_value = "4",
+        help = "bam/cram compression threads"
+    )]
+    pub writer_threads: NonZero<usize>,
+
+    #[arg(
+        long = "out-format",
+        value_enum,
+        help = "default: by the extension of the out path, bam if it is neither .sam nor .cram"
+    )]
+    pub out_format: Option<OutFormat>,
+
+    #[arg(long = "reference", help = "reference fasta, required for cram")]
+    pub reference: Option<PathBuf>,
+
+    #[arg(
+        long = "compression-level",
+        value_parser = clap::value_parser!(u32).range(0..=9),
+        help = "0-9, not for sam. default: htslib default"
+    )]
+    pub compression_level: Option<u32>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutFormat {
+    Sam,
+    Bam,
+    Cram,
+}
+
+impl G2bArgs {
+    fn out_format(&self) -> bam::Format {
+        let format = self.out_format.unwrap_or_else(|| {
+            match Path::new(&self.out_path)
+                .extension()
+                .and_then(|ext| ext.to_str())
+            {
+                Some("sam") => OutFormat::Sam,
+                Some("cram") => OutFormat::Cram,
+                _ => OutFormat::Bam,
+            }
+        });
+        match format {
+            OutFormat::Sam => bam::Format::Sam,
+            OutFormat::Bam => bam::Format::Bam,
+            OutFormat::Cram => bam::Format::Cram,
+        }
     }
 
-    pub fn new_fq_record(name: String, seq: String, qual: Vec<u8>) -> Self {
-        let mut res = ReadInfo::new_fa_record(name, seq);
-        res.qual = Some(qual);
-        res
+    /// what clap can't check, the format may come from the extension
+    fn usage_error(&self) -> Option<(ErrorKind, &'static str)> {
+        match self.out_format() {
+            bam::Format::Cram if self.reference.is_none() => Some((
+                ErrorKind::MissingRequiredArgument,
+                "--reference is required for cram output",
+            )),
+            bam::Format::Sam if self.compression_level.is_some() => Some((
+                ErrorKind::ArgumentConflict,
+                "--compression-level can't be used with sam output",
+            )),
+            _ => None,
+        }
     }
 
-    pub fn from_bam_record(
-        record: &Record,
-        qname_suffix: Option<&str>,
-        tags: &HashSet<String>,
-    ) -> Self {
-        let mut qname = unsafe { String::from_utf8_unchecked(record.qname().to_vec()) };
-        if let Some(suffix) = qname_suffix {
-            qname.push_str(suffix);
+    /// exits with the usage error, if any
+    fn validate(&self) {
+        if let Some((kind, message)) = self.usage_error() {
+            let mut command = Cli::command();
+            command.build();
+            let g2b = command.find_subcommand_mut("g2b").unwrap();
+            g2b.error(kind, message).exit();
         }
-        let record_ext = BamRecordExt::new(record);
-        let seq = unsafe { String::from_utf8_unchecked(record.seq().as_bytes()) };
+    }
+}
 
-        let dw = if tags.contains("dw") {
-            record_ext
-                .get_dw()
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+#[derive(Args, Debug)]
+pub struct Fastx2gasArgs {
+    pub in_path: String,
 
-        let ar = if tags.contains("ar") {
-            record_ext
-                .get_ar()
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+    pub out_path: String,
 
-        let cr = if tags.contains("cr") {
-            record_ext
-                .get_cr()
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+    #[arg(long = "codec-threads", default_value = "4")]
+    pub codec_threads: NonZero<usize>,
 
-        let nn = if tags.contains("nn") {
-            record_ext
-                .get_nn()
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+    #[arg(long = "o-threads", default_value = "1")]
+    pub writer_threads: NonZero<usize>,
 
-        let wd = if tags.contains("wd") {
-            record_ext
-                .get_uint_list(b"wd")
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+    #[arg(
+        long = "batch-size",
+        default_value = "1",
+        help = "number of reads per record"
+    )]
+    pub batch_size: NonZero<usize>,
 
-        let sd = if tags.contains("sd") {
-            record_ext
-                .get_uint_list(b"sd")
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+    #[arg(
+        long = "key",
+        value_parser = ["name"],
+        help = "index the records by read name so they can be looked up by key"
+    )]
+    pub key: Option<String>,
 
-        let sp = if tags.contains("sp") {
-            record_ext
-                .get_uint_list(b"sd")
-                .map(|v| v.into_iter().map(|v| v as u8).collect())
-        } else {
-            None
-        };
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FastxFormatArg {
+    Fasta,
+    Fastq,
+}
+
+#[derive(Args, Debug)]
+pub struct Gas2fastxArgs {
+    pub in_path: String,
+
+    pub out_path: String,
 
-        Self {
-            name: qname,
-            seq: seq,
-            cx: record_ext.get_cx(),
-            ch: record_ext.get_ch(),
-            np: record_ext.get_np().map(|v| v as u32),
-            rq: record_ext.get_rq(),
-            qual: Some(record_ext.get_qual().to_vec()),
-            dw: dw,
-            ar: ar,
-            cr: cr,
-            be: record_ext.get_be(),
-            nn: nn,
-            wd: wd,
-            sd: sd,
-            sp: sp,
+    #[arg(long = "in-threads", default_value = "4")]
+    pub in_threads: NonZero<usize>,
+
+    #[arg(long = "codec-threads", default_value = "4")]
+    pub codec_threads: NonZero<usize>,
+
+    #[arg(
+        long = "format",
+        value_enum,
+        help = "default: by the extension of the out path"
+    )]
+    pub format: Option<FastxFormatArg>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+}
+
+impl Gas2fastxArgs {
+    fn format(&self) -> FastxFormat {
+        match self.format {
+            Some(FastxFormatArg::Fasta) => FastxFormat::Fasta,
+            Some(FastxFormatArg::Fastq) => FastxFormat::Fastq,
+            None => FastxFormat::from_path(&self.out_path).unwrap_or_else(|| {
+                panic!(
+                    "can't tell fasta/fastq from {}, use --format",
+                    self.out_path
+                )
+            }),
         }
     }
+}
 
-    pub fn to_record(&self) -> Record {
-        let mut record = Record::new();
+#[derive(Args, Debug)]
+pub struct FilterArgs {
+    #[arg(
+        long = "filter",
+        help = "e.g. rq>=0.99, np>=3, len>=1000. on len/rq/np/ch/cx/mapq, can be repeated. reads without the field are dropped"
+    )]
+    pub stats: Vec<StatFilter>,
 
-        // 设置 qname
+    #[arg(
+        long = "include-names",
+        help = "only keep the reads listed in the file, one name per line"
+    )]
+    pub include_names: Option<PathBuf>,
 
-        // 设置 seq
-        record.set(
-            self.name.as_bytes(),
-            None,
-            self.seq.as_bytes(),
-            self.qual.as_ref().unwrap(),
-        );
-        // 设置 tags
-        macro_rules! push_aux {
-            ($tag:expr, $value:expr) => {
-                record.push_aux($tag, $value).unwrap();
-            };
-        }
+    #[arg(
+        long = "exclude-names",
+        help = "drop the reads listed in the file, one name per line"
+    )]
+    pub exclude_names: Option<PathBuf>,
+}
 
-        if let Some(cx) = self.cx {
-            push_aux!(b"cx", Aux::U8(cx));
-        }
-        if let Some(ch) = self.ch {
-            push_aux!(b"ch", Aux::U32(ch));
-        }
-        if let Some(np) = self.np {
-            push_aux!(b"np", Aux::U32(np));
-        }
-        if let Some(rq) = self.rq {
-            push_aux!(b"rq", Aux::Float(rq));
-        }
-        if let Some(be) = &self.be {
-            push_aux!(b"be", Aux::ArrayU32(be.into()));
-        }
-        if let Some(dw) = &self.dw {
-            push_aux!(b"dw", Aux::ArrayU8(dw.into()));
-        }
-        if let Some(ar) = &self.ar {
-            push_aux!(b"ar", Aux::ArrayU8(ar.into()));
-        }
-        if let Some(cr) = &self.cr {
-            push_aux!(b"cr", Aux::ArrayU8(cr.into()));
-        }
-        if let Some(nn) = &self.nn {
-            push_aux!(b"nn", Aux::ArrayU8(nn.into()));
-        }
-        if let Some(wd) = &self.wd {
-            push_aux!(b"wd", Aux::ArrayU8(wd.into()));
-        }
-        if let Some(sd) = &self.sd {
-            push_aux!(b"sd", Aux::ArrayU8(sd.into()));
-        }
-        if let Some(sp) = &self.sp {
-            push_aux!(b"sp", Aux::ArrayU8(sp.into()));
-        }
+impl FilterArgs {
+    fn read_filter(&self) -> ReadFilter {
+        ReadFilter::new(
+            self.stats.clone(),
+            self.include_names.as_ref().map(read_name_list),
+            self.exclude_names
+                .as_ref()
+                .map(read_name_list)
+                .unwrap_or_default(),
+        )
+    }
+}
+
+/// kept/dropped reads of the codec workers, and the records of the gas blocks the zone maps skip
+#[derive(Debug, Default)]
+struct FilterCounts {
+    kept: AtomicU64,
+    dropped: AtomicU64,
+    skipped_records: AtomicU64,
+}
 
-        record
+impl FilterCounts {
+    fn add(&self, kept: u64, dropped: u64) {
+        self.kept.fetch_add(kept, Ordering::Relaxed);
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    fn report(&self, filter: &ReadFilter) {
+        if !filter.is_empty() {
+            eprintln!(
+                "filter: kept {} reads, dropped {}",
+                self.kept.load(Ordering::Relaxed),
+                self.dropped.load(Ordering::Relaxed)
+            );
+            let skipped_records = self.skipped_records.load(Ordering::Relaxed);
+            if skipped_records > 0 {
+                // a record may hold more than one read, so they are not added to the dropped reads
+                eprintln!(
+                    "filter: skipped {} records in the blocks without a matching read",
+                    skipped_records
+                );
+            }
+        }
     }
 }
 
-#[derive(Debug, bincode::Encode, bincode::Decode)]
-pub struct BatchReads(pub Vec<ReadInfo>);
-impl Deref for BatchReads {
-    type Target = Vec<ReadInfo>;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+/// `-` writes a gas stream to stdout, e.g. bam-gas-cvt b2g in.bam - | gzip.
+/// returns the sender of the records and a function that waits until they are written
+fn gas_writer(
+    out_path: &str,
+    threads: NonZero<usize>,
+    sections: Vec<(&str, Vec<u8>)>,
+) -> (Sender<GasRecord>, Box<dyn FnOnce()>) {
+    if out_path == "-" {
+        let (writer, sender) = GasStreamWriter::new_writer(std::io::stdout());
+        for (name, data) in sections {
+            writer.add_section(name, data);
+        }
+        writer.start_write_worker();
+        (sender, Box::new(move || writer.wait_for_write_done()))
+    } else {
+        let (writer, sender) = GasFileWriter::new_writer(out_path, threads);
+        for (name, data) in sections {
+            writer.add_section(name, data);
+        }
+        writer.start_write_worker();
+        (sender, Box::new(move || writer.wait_for_write_done()))
     }
 }
 
-impl DerefMut for BatchReads {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+/// `-` reads a gas stream from stdin. returns the receiver of the records and the sections,
+/// a stream only has the sections that are written ahead of its records.
+/// panics before any record is read if the reads are encoded in another format
+fn gas_reader<'scope>(
+    scope: &'scope thread::Scope<'scope, '_>,
+    in_path: &str,
+    threads: NonZero<usize>,
+    (filter, counts): (&ReadFilter, &FilterCounts),
+) -> (Receiver<Vec<u8>>, HashMap<String, Vec<u8>>) {
+    if in_path == "-" {
+        let (sender, recv) = crossbeam::channel::bounded(1000);
+        let reader = GasStreamReader::new(std::io::stdin());
+        let sections = reader
+            .section_names()
+            .into_iter()
+            .map(|name| (name.to_string(), reader.section(name).unwrap().to_vec()))
+            .collect::<HashMap<_, _>>();
+        check_reads_format(sections.get(READS_FORMAT_SECTION).map(Vec::as_slice));
+        scope.spawn(move || reader.read_worker(sender));
+        (recv, sections)
+    } else {
+        let (reader, recv) = GasFileReader::new_reader(in_path, threads);
+        let sections = reader
+            .section_names()
+            .into_iter()
+            .map(|name| (name.to_string(), reader.section(name).unwrap()))
+            .collect::<HashMap<_, _>>();
+        check_reads_format(sections.get(READS_FORMAT_SECTION).map(Vec::as_slice));
+        // the zone maps skip the blocks without any matching read
+        let skipped_records = reader.retain_blocks_matching(&filter.stats);
+        counts
+            .skipped_records
+            .fetch_add(skipped_records, Ordering::Relaxed);
+        reader.start_read_worker();
+        (recv, sections)
     }
 }
 
-fn bam_reader<P>(
-    bam_path: P,
-    bam_threads: usize,
+fn bam_reader(
+    mut bam_reader: bam::Reader,
+    bam_path: &str,
     sender: Sender<bam::Record>,
-    rep_times: Option<usize>,
-) where
-    P: AsRef<Path>,
-{
-    let mut bam_reader = bam::Reader::from_path(&bam_path).expect(&format!("read error"));
-    bam_reader.set_threads(bam_threads).unwrap();
-
-    let pb = get_spin_pb(
-        format!("reading {}", bam_path.as_ref().to_str().unwrap()),
-        DEFAULT_INTERVAL,
-    );
-    let rep_times = rep_times.unwrap_or(1);
+    rep_times: Option<NonZero<usize>>,
+) {
+    let pb = get_spin_pb(format!("reading {}", bam_path), DEFAULT_INTERVAL);
+    let rep_times = rep_times.map_or(1, NonZero::get);
     loop {
         let mut record = bam::Record::new();
         if let Some(Ok(_)) = bam_reader.read(&mut record) {
@@ -276,101 +474,247 @@ fn bam_reader<P>(
     pb.finish();
 }
 
-fn enc_worker(recv: Receiver<bam::Record>, sender: Sender<Vec<u8>>, batch_size: Option<usize>) {
-    let mut tags = HashSet::new();
-    tags.insert("dw".to_string());
-    tags.insert("ar".to_string());
-    tags.insert("cr".to_string());
-    tags.insert("be".to_string());
-    tags.insert("nn".to_string());
-    tags.insert("wd".to_string());
-    tags.insert("sd".to_string());
-    tags.insert("sp".to_string());
-
-    let batch_size = batch_size.unwrap_or(1);
-    let cfg = get_bincode_cfg();
+/// batches the reads that pass the filter, `to_read` converts what the reader sends
+fn enc_worker<T, F>(
+    recv: Receiver<T>,
+    sender: Sender<GasRecord>,
+    batch_size: NonZero<usize>,
+    key: Option<&str>,
+    (filter, counts): (&ReadFilter, &FilterCounts),
+    to_read: F,
+) where
+    F: Fn(T) -> ReadInfo,
+{
+    let batch_size = batch_size.get();
     let mut record_batch = BatchReads(vec![]);
     let mut tot_len = 0;
+    let (mut kept, mut dropped) = (0, 0);
     for record in recv {
-        let read = ReadInfo::from_bam_record(&record, None, &tags);
+        let read = to_read(record);
+        if !filter.keep(&read) {
+            dropped += 1;
+            continue;
+        }
+        kept += 1;
         record_batch.push(read);
         if record_batch.len() == batch_size {
-            let serial = bincode::encode_to_vec(&record_batch, cfg).unwrap();
-            tot_len += serial.len();
+            let gas_record = record_batch.to_gas_record(key);
+            tot_len += gas_record.data.len();
 
-            sender.send(serial).unwrap();
+            sender.send(gas_record).unwrap();
             record_batch = BatchReads(vec![]);
         }
     }
-    println!("len:{}", tot_len);
+    eprintln!("len:{}", tot_len);
+    counts.add(kept, dropped);
 
     if !record_batch.is_empty() {
-        let serial = bincode::encode_to_vec(&record_batch, cfg).unwrap();
-        sender.send(serial).unwrap();
+        sender.send(record_batch.to_gas_record(key)).unwrap();
     }
 }
 
-fn b2g(cli: &Cli) {
-    let out_path = cli.get_out_path();
-    println!("{:?}", out_path);
-    std::thread::scope(|thread_scope| {
-        let (writer, sender4writer) =
-            GasFileWriter::new_writer(&out_path, NonZero::new(cli.writer_threads).unwrap());
-        writer.start_write_worker();
+/// one record per group of the reads that pass the filter, a group without such reads is skipped
+fn group_enc_worker<T, F>(
+    recv: Receiver<Vec<T>>,
+    sender: Sender<GasRecord>,
+    key: Option<&str>,
+    (filter, counts): (&ReadFilter, &FilterCounts),
+    to_read: F,
+) where
+    F: Fn(T) -> ReadInfo,
+{
+    let (mut kept, mut dropped) = (0, 0);
+    for group in recv {
+        let num_reads = group.len();
+        let reads = BatchReads(
+            group
+                .into_iter()
+                .map(&to_read)
+                .filter(|read| filter.keep(read))
+                .collect(),
+        );
+        kept += reads.len();
+        dropped += num_reads - reads.len();
+        if !reads.is_empty() {
+            sender.send(reads.to_gas_record(key)).unwrap();
+        }
+    }
+    counts.add(kept as u64, dropped as u64);
+}
+
+fn b2g(cli: &B2gArgs) {
+    eprintln!("{:?}", cli.out_path);
+    let mut reader = bam::Reader::from_path(&cli.in_path)
+        .unwrap_or_else(|err| panic!("open {} error. {}", cli.in_path, err));
+    reader.set_threads(cli.in_threads.get()).unwrap();
+    // g2b restores it, so the read groups and programs of the source survive the round trip
+    let header = reader.header().as_bytes().to_vec();
+    let tags = cli.tag_selection();
+    eprintln!("tags: {}", tags);
+    let tags_section = bincode::encode_to_vec(&tags, get_bincode_cfg()).unwrap();
+    let filter = cli.filter.read_filter();
+    let counts = FilterCounts::default();
+
+    thread::scope(|thread_scope| {
+        let (sender4writer, wait_for_write_done) = gas_writer(
+            &cli.out_path,
+            cli.writer_threads,
+            vec![
+                (READS_FORMAT_SECTION, reads_format_section()),
+                (BAM_HEADER_SECTION, header),
+                (TAG_SELECTION_SECTION, tags_section),
+            ],
+        );
         let (bam_record_sender, bam_record_recv) = crossbeam::channel::bounded(1000);
         thread_scope.spawn({
-            let bam_path = cli.in_path.clone();
-            let bam_threads = cli.in_threads;
-            let rep_times = cli.rep_times.clone();
+            let bam_path = cli.in_path.as_str();
+            let rep_times = cli.rep_times;
             move || {
-                bam_reader(&bam_path, bam_threads, bam_record_sender, rep_times);
+                bam_reader(reader, bam_path, bam_record_sender, rep_times);
             }
         });
 
-        for _ in 0..cli.codec_threads {
+        // the subreads of a zmw are grouped before the codec workers split the records
+        let zmw_recv = cli.group_by.map(|GroupBy::Zmw| {
+            let (group_sender, group_recv) = crossbeam::channel::bounded(100);
+            let recv = bam_record_recv.clone();
+            thread_scope.spawn(move || {
+                for group in ZmwGroups::new(recv.into_iter()) {
+                    group_sender.send(group).unwrap();
+                }
+            });
+            group_recv
+        });
+
+        for _ in 0..cli.codec_threads.get() {
             thread_scope.spawn({
                 let recv = bam_record_recv.clone();
+                let zmw_recv = zmw_recv.clone();
                 let sender = sender4writer.clone();
-                let batch_size = cli.batch_size.clone();
+                let batch_size = cli.batch_size;
+                let key = cli.key.as_deref();
+                let tags = &tags;
+                let filter = (&filter, &counts);
                 move || {
-                    enc_worker(recv, sender, batch_size);
+                    let to_read =
+                        |record: bam::Record| ReadInfo::from_bam_record(&record, None, tags);
+                    match zmw_recv {
+                        Some(zmw_recv) => group_enc_worker(zmw_recv, sender, key, filter, to_read),
+                        None => enc_worker(recv, sender, batch_size, key, filter, to_read),
+                    }
                 }
             });
         }
         drop(sender4writer);
-        writer.wait_for_write_done();
+        wait_for_write_done();
     });
+    counts.report(&filter);
 }
 
-fn decode_worker(sender: Sender<bam::Record>, recv: Receiver<Vec<u8>>) {
+/// decodes the records, `from_read` converts the reads that pass the filter for the writer
+fn decode_worker<T, F>(
+    sender: Sender<T>,
+    recv: Receiver<Vec<u8>>,
+    (filter, counts): (&ReadFilter, &FilterCounts),
+    from_read: F,
+) where
+    F: Fn(&ReadInfo) -> T,
+{
     let cfg = get_bincode_cfg();
+    let (mut kept, mut dropped) = (0, 0);
     for data in recv {
         let (batch_records, _nbytes): (BatchReads, usize) =
             bincode::decode_from_slice(&data, cfg).unwrap();
         batch_records.iter().for_each(|read| {
-            sender.send(read.to_record()).unwrap();
+            if filter.keep(read) {
+                kept += 1;
+                sender.send(from_read(read)).unwrap();
+            } else {
+                dropped += 1;
+            }
         });
     }
+    counts.add(kept, dropped);
 }
 
-fn bam_writer<P>(fname: P, recv: Receiver<bam::Record>)
-where
-    P: AsRef<Path>,
-{
-    let mut header = bam::Header::new();
-    let mut hd = bam::header::HeaderRecord::new(b"HD");
-    hd.push_tag(b"VN", "1.5");
-    hd.push_tag(b"SO", "unknown");
-    header.push_record(&hd);
-
-    let mut hd = bam::header::HeaderRecord::new(b"SQ");
-    hd.push_tag(b"SN", "chr1");
-    hd.push_tag(b"LN", "1234");
-    header.push_record(&hd);
-
-    let mut bam_writer = bam::Writer::from_path(&fname, &header, bam::Format::Bam).unwrap();
-    bam_writer.set_threads(4).unwrap();
-    let pb = get_spin_pb(format!("writing {}", fname.as_ref().to_str().unwrap()), DEFAULT_INTERVAL);
+/// the header of the source bam plus a @PG line for this conversion.
+/// files written before the header was kept get a bare @HD line
+fn bam_header(source: Option<&[u8]>) -> bam::Header {
+    let mut header = match source {
+        Some(text) => bam::Header::from_template(&bam::HeaderView::from_bytes(text)),
+        None => {
+            let mut header = bam::Header::new();
+            let mut hd = bam::header::HeaderRecord::new(b"HD");
+            hd.push_tag(b"VN", "1.5");
+            hd.push_tag(b"SO", "unknown");
+            header.push_record(&hd);
+            header
+        }
+    };
+
+    let pg_ids = header
+        .to_hashmap()
+        .remove("PG")
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|mut pg| pg.remove("ID"))
+        .collect::<Vec<_>>();
+    let mut id = "bam-gas-cvt".to_string();
+    let mut suffix = 0;
+    while pg_ids.contains(&id) {
+        suffix += 1;
+        id = format!("bam-gas-cvt.{}", suffix);
+    }
+    let mut pg = bam::header::HeaderRecord::new(b"PG");
+    pg.push_tag(b"ID", &id);
+    pg.push_tag(b"PN", "bam-gas-cvt");
+    if let Some(prev) = pg_ids.last() {
+        pg.push_tag(b"PP", prev);
+    }
+    pg.push_tag(b"VN", env!("CARGO_PKG_VERSION"));
+    pg.push_tag(b"CL", std::env::args().collect::<Vec<_>>().join(" "));
+    header.push_record(&pg);
+    header
+}
+
+/// adds UR:file:{reference} to the @SQ lines without an UR tag
+fn with_reference_url(header: &bam::Header, reference: &Path) -> bam::Header {
+    let reference = std::fs::canonicalize(reference)
+        .unwrap_or_else(|err| panic!("open {:?} error. {}", reference, err));
+    let mut text = String::new();
+    for line in String::from_utf8(header.to_bytes()).unwrap().lines() {
+        text.push_str(line);
+        if line.starts_with("@SQ\t") && !line.split('\t').any(|field| field.starts_with("UR:")) {
+            text.push_str(&format!("\tUR:file:{}", reference.display()));
+        }
+        text.push('\n');
+    }
+    bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()))
+}
+
+fn bam_writer(cli: &G2bArgs, header: &bam::Header, recv: Receiver<bam::Record>) {
+    // the cram header is written before set_reference, so htslib can only find the reference
+    // by the UR tags there. without them it embeds the reference in the file
+    let cram_header;
+    let header = match &cli.reference {
+        Some(reference) if cli.out_format() == bam::Format::Cram => {
+            cram_header = with_reference_url(header, reference);
+            &cram_header
+        }
+        _ => header,
+    };
+    let mut bam_writer = bam::Writer::from_path(&cli.out_path, header, cli.out_format())
+        .unwrap_or_else(|err| panic!("create {} error. {}", cli.out_path, err));
+    bam_writer.set_threads(cli.writer_threads.get()).unwrap();
+    if let Some(reference) = &cli.reference {
+        bam_writer.set_reference(reference).unwrap();
+    }
+    if let Some(level) = cli.compression_level {
+        bam_writer
+            .set_compression_level(bam::CompressionLevel::Level(level))
+            .unwrap();
+    }
+    let pb = get_spin_pb(format!("writing {}", cli.out_path), DEFAULT_INTERVAL);
     for record in recv {
         bam_writer.write(&record).unwrap();
         pb.inc(1);
@@ -378,31 +722,372 @@ where
     pb.finish();
 }
 
-fn g2b(cli: &Cli) {
-    let (reader, recv) =
-        GasFileReader::new_reader(&cli.in_path, NonZero::new(cli.in_threads).unwrap());
-    reader.start_read_worker();
-    std::thread::scope(|scope| {
+fn print_tag_selection(section: Option<&[u8]>) {
+    match section {
+        Some(data) => {
+            let (tags, _nbytes): (TagSelection, usize) =
+                bincode::decode_from_slice(data, get_bincode_cfg()).unwrap();
+            eprintln!("tags: {}", tags);
+        }
+        None => eprintln!("tags: unknown, the file is written without a tag selection"),
+    }
+}
+
+fn g2b(cli: &G2bArgs) {
+    let filter = cli.filter.read_filter();
+    let counts = FilterCounts::default();
+    thread::scope(|scope| {
+        let (recv, sections) = gas_reader(scope, &cli.in_path, cli.in_threads, (&filter, &counts));
+        let header = bam_header(sections.get(BAM_HEADER_SECTION).map(Vec::as_slice));
+        print_tag_selection(sections.get(TAG_SELECTION_SECTION).map(Vec::as_slice));
+        let (decode_sender, decode_recv) = crossbeam::channel::bounded(1000);
+        for _ in 0..cli.codec_threads.get() {
+            scope.spawn({
+                let recv = recv.clone();
+                let sender = decode_sender.clone();
+                let filter = (&filter, &counts);
+                move || {
+                    decode_worker(sender, recv, filter, ReadInfo::to_record);
+                }
+            });
+        }
+        drop(decode_sender);
+        bam_writer(cli, &header, decode_recv);
+    });
+    counts.report(&filter);
+}
+
+fn fastx_reader(fastx_path: &str, sender: Sender<ReadInfo>) {
+    let pb = get_spin_pb(format!("reading {}", fastx_path), DEFAULT_INTERVAL);
+    for read in FastxReader::from_path(fastx_path) {
+        pb.inc(1);
+        sender.send(read).unwrap();
+    }
+    pb.finish();
+}
+
+fn fastx2gas(cli: &Fastx2gasArgs) {
+    let filter = cli.filter.read_filter();
+    let counts = FilterCounts::default();
+    thread::scope(|thread_scope| {
+        let (sender4writer, wait_for_write_done) = gas_writer(
+            &cli.out_path,
+            cli.writer_threads,
+            vec![(READS_FORMAT_SECTION, reads_format_section())],
+        );
+        let (read_sender, read_recv) = crossbeam::channel::bounded(1000);
+        thread_scope.spawn(move || fastx_reader(&cli.in_path, read_sender));
+
+        for _ in 0..cli.codec_threads.get() {
+            thread_scope.spawn({
+                let recv = read_recv.clone();
+                let sender = sender4writer.clone();
+                let key = cli.key.as_deref();
+                let filter = (&filter, &counts);
+                move || {
+                    enc_worker(recv, sender, cli.batch_size, key, filter, |read| read);
+                }
+            });
+        }
+        drop(sender4writer);
+        wait_for_write_done();
+    });
+    counts.report(&filter);
+}
+
+fn gas2fastx(cli: &Gas2fastxArgs) {
+    let format = cli.format();
+    let filter = cli.filter.read_filter();
+    let counts = FilterCounts::default();
+    thread::scope(|scope| {
+        let (recv, _sections) = gas_reader(scope, &cli.in_path, cli.in_threads, (&filter, &counts));
         let (decode_sender, decode_recv) = crossbeam::channel::bounded(1000);
-        for _ in 0..cli.codec_threads {
+        for _ in 0..cli.codec_threads.get() {
             scope.spawn({
                 let recv = recv.clone();
                 let sender = decode_sender.clone();
+                let filter = (&filter, &counts);
                 move || {
-                    decode_worker(sender, recv);
+                    decode_worker(sender, recv, filter, |read| {
+                        let mut text = vec![];
+                        write_fastx_record(&mut text, read, format);
+                        text
+                    });
                 }
             });
         }
         drop(decode_sender);
-        bam_writer(&cli.get_out_path(), decode_recv);
+
+        let mut writer = create_fastx_writer(&cli.out_path);
+        let pb = get_spin_pb(format!("writing {}", cli.out_path), DEFAULT_INTERVAL);
+        for text in decode_recv {
+            writer.write_all(&text).unwrap();
+            pb.inc(1);
+        }
+        writer.flush().unwrap();
+        pb.finish();
     });
+    counts.report(&filter);
 }
 
 fn main() {
     let cli = Cli::parse();
-    match cli.mode.as_ref() {
-        "b2g" => b2g(&cli),
-        "g2b" => g2b(&cli),
-        mode => panic!("invalid mode. {}. only b2g/g2b are valid", mode),
+    match &cli.command {
+        Command::B2g(args) => b2g(args),
+        Command::G2b(args) => {
+            args.validate();
+            g2b(args)
+        }
+        Command::Fastx2gas(args) => fastx2gas(args),
+        Command::Gas2fastx(args) => gas2fastx(args),
     };
 }
+
+#[cfg(test)]
+mod test {
+    use clap::{CommandFactory, Parser, error::ErrorKind};
+
+    use rust_htslib::bam::{self, Read, record::CigarString};
+    use tempfile::TempDir;
+
+    use super::{Cli, Command, G2bArgs, bam_header, bam_writer};
+
+    #[test]
+    fn test_subcommands() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from([
+            "bam-gas-cvt",
+            "b2g",
+            "in.bam",
+            "out.gas",
+            "--rep-times",
+            "3",
+        ])
+        .unwrap();
+        assert!(matches!(cli.command, Command::B2g(args) if args.rep_times.unwrap().get() == 3));
+        let cli = Cli::try_parse_from(["bam-gas-cvt", "g2b", "in.gas", "out.cram"]).unwrap();
+        assert!(
+            matches!(cli.command, Command::G2b(args) if args.out_format() == rust_htslib::bam::Format::Cram)
+        );
+
+        // flags of one subcommand are rejected by the others
+        assert!(
+            Cli::try_parse_from([
+                "bam-gas-cvt",
+                "g2b",
+                "in.gas",
+                "out.bam",
+                "--rep-times",
+                "3"
+            ])
+            .is_err()
+        );
+        assert!(
+            Cli::try_parse_from([
+                "bam-gas-cvt",
+                "b2g",
+                "in.bam",
+                "out.gas",
+                "--group-by",
+                "zmw",
+                "--batch-size",
+                "2"
+            ])
+            .is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["bam-gas-cvt", "--mode", "b2g", "in.bam", "out.gas"]).is_err()
+        );
+    }
+
+    fn g2b_args(args: &[&str]) -> G2bArgs {
+        let cli = Cli::try_parse_from(["bam-gas-cvt", "g2b", "in.gas"].iter().chain(args)).unwrap();
+        match cli.command {
+            Command::G2b(args) => args,
+            command => panic!("not g2b. {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_g2b_out_format() {
+        for (out, format) in [
+            ("out.sam", bam::Format::Sam),
+            ("out.bam", bam::Format::Bam),
+            ("out.cram", bam::Format::Cram),
+            ("out", bam::Format::Bam),
+        ] {
+            assert_eq!(g2b_args(&[out]).out_format(), format);
+        }
+        // --out-format wins over the extension
+        assert_eq!(
+            g2b_args(&["out.bam", "--out-format", "sam"]).out_format(),
+            bam::Format::Sam
+        );
+
+        assert_eq!(
+            g2b_args(&["out.cram"]).usage_error().map(|(kind, _)| kind),
+            Some(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(
+            g2b_args(&["out.bam", "--out-format", "cram"])
+                .usage_error()
+                .map(|(kind, _)| kind),
+            Some(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(
+            g2b_args(&["out.cram", "--reference", "ref.fa"]).usage_error(),
+            None
+        );
+        assert_eq!(
+            g2b_args(&["out.sam", "--compression-level", "1"])
+                .usage_error()
+                .map(|(kind, _)| kind),
+            Some(ErrorKind::ArgumentConflict)
+        );
+        assert_eq!(
+            g2b_args(&["out.bam", "--compression-level", "1"]).usage_error(),
+            None
+        );
+        assert!(
+            Cli::try_parse_from([
+                "bam-gas-cvt",
+                "g2b",
+                "in.gas",
+                "out.bam",
+                "--compression-level",
+                "10"
+            ])
+            .is_err()
+        );
+    }
+
+    /// writes the records with bam_writer, returns the path
+    fn write_bam(dir: &TempDir, args: &[&str], records: &[bam::Record]) -> std::path::PathBuf {
+        let args = g2b_args(args);
+        let header_view = bam::HeaderView::from_bytes(b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:1000\n");
+        let header = bam::Header::from_template(&header_view);
+        let (sender, recv) = crossbeam::channel::unbounded();
+        for record in records {
+            sender.send(record.clone()).unwrap();
+        }
+        drop(sender);
+        bam_writer(&args, &header, recv);
+        dir.path().join(&args.out_path)
+    }
+
+    #[test]
+    fn test_g2b_bam_writer() {
+        let dir = TempDir::new().unwrap();
+        let out = |name: &str| dir.path().join(name).to_str().unwrap().to_string();
+        let reference = dir.path().join("ref.fa");
+        let chr1 = "ACGT".repeat(250);
+        std::fs::write(&reference, format!(">chr1\n{}\n", chr1)).unwrap();
+        let records = (0..200)
+            .map(|i| {
+                let mut record = bam::Record::new();
+                let cigar = CigarString::try_from("16M").unwrap();
+                let pos = i * 4;
+                record.set(
+                    format!("read/{}", i).as_bytes(),
+                    Some(&cigar),
+                    &chr1.as_bytes()[pos..pos + 16],
+                    &[30; 16],
+                );
+                record.set_flags(0);
+                record.set_tid(0);
+                record.set_pos(pos as i64);
+                record.set_bin(4681);
+                record.set_mapq(60);
+                record.set_mtid(-1);
+                record.set_mpos(-1);
+                record
+            })
+            .collect::<Vec<_>>();
+
+        // the format is inferred from the extension, cram is written against the reference
+        let sam = write_bam(&dir, &[&out("out.sam")], &records);
+        assert!(std::fs::read(&sam).unwrap().starts_with(b"@HD"));
+        let cram = write_bam(
+            &dir,
+            &[&out("out.cram"), "--reference", reference.to_str().unwrap()],
+            &records,
+        );
+        assert!(std::fs::read(&cram).unwrap().starts_with(b"CRAM"));
+        let mut reader = bam::Reader::from_path(&cram).unwrap();
+        reader.set_reference(&reference).unwrap();
+        let back = reader.records().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(back.len(), records.len());
+        assert_eq!(back[7].seq().as_bytes(), records[7].seq().as_bytes());
+        assert_eq!(back[7].qname(), records[7].qname());
+        let header = String::from_utf8(reader.header().as_bytes().to_vec()).unwrap();
+        assert!(header.contains(&format!(
+            "UR:file:{}",
+            std::fs::canonicalize(&reference).unwrap().display()
+        )));
+
+        // the compression level is applied
+        let level = |level: &str| {
+            let path = write_bam(
+                &dir,
+                &[
+                    &out(&format!("level{}.bam", level)),
+                    "--compression-level",
+                    level,
+                ],
+                &records,
+            );
+            assert!(std::fs::read(&path).unwrap().starts_with(&[0x1f, 0x8b]));
+            std::fs::metadata(path).unwrap().len()
+        };
+        assert!(level("0") > level("9"));
+    }
+
+    /// (ID, PP) of the @PG lines
+    fn pg_chain(header: &bam::Header) -> Vec<(String, Option<String>)> {
+        header
+            .to_hashmap()
+            .remove("PG")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|mut pg| (pg.remove("ID").unwrap(), pg.remove("PP")))
+            .collect()
+    }
+
+    #[test]
+    fn test_bam_header() {
+        let header = bam_header(None);
+        let text = String::from_utf8(header.to_bytes()).unwrap();
+        assert!(text.starts_with("@HD\tVN:1.5\tSO:unknown\n@PG\tID:bam-gas-cvt\tPN:bam-gas-cvt\t"));
+        assert_eq!(pg_chain(&header), vec![("bam-gas-cvt".to_string(), None)]);
+
+        // the source lines are kept and the new @PG follows the last one
+        let source = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n@PG\tID:ccs\tPN:ccs\n";
+        let header = bam_header(Some(source.as_bytes()));
+        let text = String::from_utf8(header.to_bytes()).unwrap();
+        assert!(text.starts_with(source));
+        assert_eq!(
+            pg_chain(&header),
+            vec![
+                ("ccs".to_string(), None),
+                ("bam-gas-cvt".to_string(), Some("ccs".to_string()))
+            ]
+        );
+
+        // a converted file converted again gets a suffixed ID
+        let header = bam_header(Some(&header.to_bytes()));
+        let header = bam_header(Some(&header.to_bytes()));
+        assert_eq!(
+            pg_chain(&header),
+            vec![
+                ("ccs".to_string(), None),
+                ("bam-gas-cvt".to_string(), Some("ccs".to_string())),
+                ("bam-gas-cvt.1".to_string(), Some("bam-gas-cvt".to_string())),
+                (
+                    "bam-gas-cvt.2".to_string(),
+                    Some("bam-gas-cvt.1".to_string())
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::NonZero,
+};
+
+use clap::{Parser, ValueEnum};
+use gas::{
+    bloom::stable_hash64,
+    io::v1::{GasFileReader, get_bincode_cfg},
+    reads::{BatchReads, READS_FORMAT_SECTION, ReadInfo, check_reads_format},
+};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffMode {
+    /// compare the record payloads byte by byte
+    Bytes,
+    /// decode BatchReads and compare the reads field by field, matched by read name
+    Reads,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DiffOrder {
+    /// the order does not matter
+    Multiset,
+    /// the n-th record (or read) of a is compared with the n-th of b
+    Sequence,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "compare two gas files. exits with 1 if they differ",
+    long_about = None
+)]
+pub struct Cli {
+    pub a: String,
+    pub b: String,
+
+    #[arg(long = "mode", value_enum, default_value_t = DiffMode::Reads)]
+    pub mode: DiffMode,
+
+    #[arg(long = "order", value_enum, default_value_t = DiffOrder::Multiset)]
+    pub order: DiffOrder,
+
+    #[arg(
+        long = "max-report",
+        default_value_t = 20,
+        help = "print at most N differences, all of them are counted in the summary"
+    )]
+    pub max_report: usize,
+}
+
+#[derive(Debug, Default)]
+struct DiffSummary {
+    num_a: u64,
+    num_b: u64,
+    identical: u64,
+    different: u64,
+    missing: u64, // only in a
+    extra: u64,   // only in b
+    fields: BTreeMap<String, u64>,
+    reports: Vec<String>, // the first max_report differences
+    reported: usize,
+}
+
+impl DiffSummary {
+    fn report(&mut self, max_report: usize, line: String) {
+        if self.reported < max_report {
+            self.reports.push(line);
+        }
+        self.reported += 1;
+    }
+
+    fn has_diff(&self) -> bool {
+        self.different + self.missing + self.extra > 0
+    }
+}
+
+fn open(path: &str) -> std::sync::Arc<GasFileReader> {
+    GasFileReader::new_reader(path, NonZero::new(1).unwrap()).0
+}
+
+/// two hashes and the length, so a collision is unlikely enough to be ignored
+type RecordHash = (u64, u64, usize);
+
+fn record_hash(data: &[u8]) -> RecordHash {
+    (stable_hash64(data, 0), stable_hash64(data, 1), data.len())
+}
+
+fn diff_bytes(cli: &Cli, summary: &mut DiffSummary) {
+    let (a, b) = (open(&cli.a), open(&cli.b));
+    match cli.order {
+        DiffOrder::Sequence => {
+            let mut records_a = a.records();
+            let mut records_b = b.records();
+            let mut idx = 0_u64;
+            loop {
+                match (records_a.next(), records_b.next()) {
+                    (None, None) => break,
+                    (Some(_), None) => {
+                        summary.num_a += 1;
+                        summary.missing += 1;
+                        summary.report(cli.max_report, format!("record {}: only in a", idx));
+                    }
+                    (None, Some(_)) => {
+                        summary.num_b += 1;
+                        summary.extra += 1;
+                        summary.report(cli.max_report, format!("record {}: only in b", idx));
+                    }
+                    (Some(data_a), Some(data_b)) => {
+                        summary.num_a += 1;
+                        summary.num_b += 1;
+                        if data_a == data_b {
+                            summary.identical += 1;
+                        } else {
+                            summary.different += 1;
+                            let first_diff = data_a
+                                .iter()
+                                .zip(&data_b)
+                                .position(|(x, y)| x != y)
+                                .unwrap_or(data_a.len().min(data_b.len()));
+                            summary.report(
+                                cli.max_report,
+                                format!(
+                                    "record {}: len {} vs {}, first difference at byte {}",
+                                    idx,
+                                    data_a.len(),
+                                    data_b.len(),
+                                    first_diff
+                                ),
+                            );
+                        }
+                    }
+                }
+                idx += 1;
+            }
+        }
+        DiffOrder::Multiset => {
+            // record hash -> indices in a, so only the hashes are held in memory
+            let mut only_a: HashMap<RecordHash, Vec<u64>> = HashMap::new();
+            for (idx, data) in a.records().enumerate() {
+                summary.num_a += 1;
+                only_a
+                    .entry(record_hash(&data))
+                    .or_default()
+                    .push(idx as u64);
+            }
+            for (idx, data) in b.records().enumerate() {
+                summary.num_b += 1;
+                let matched = only_a
+                    .get_mut(&record_hash(&data))
+                    .and_then(|indices| indices.pop());
+                if matched.is_some() {
+                    summary.identical += 1;
+                } else {
+                    summary.extra += 1;
+                    summary.report(
+                        cli.max_report,
+                        format!("record {} of b: not in a, len {}", idx, data.len()),
+                    );
+                }
+            }
+            let mut missing = only_a.into_values().flatten().collect::<Vec<_>>();
+            missing.sort();
+            for idx in missing {
+                summary.missing += 1;
+                summary.report(cli.max_report, format!("record {} of a: not in b", idx));
+            }
+        }
+    }
+}
+
+/// reads of a file in order, each with the bytes it is encoded in
+fn reads(reader: &GasFileReader) -> impl Iterator<Item = (ReadInfo, Vec<u8>)> + '_ {
+    reader.records().flat_map(|data| {
+        BatchReads::decode_iter(&data)
+            .map(|(read, encoded)| (read, encoded.to_vec()))
+            .collect::<Vec<_>>()
+    })
+}
+
+fn fields(read: &ReadInfo) -> Map<String, Value> {
+    match serde_json::to_value(read).unwrap() {
+        Value::Object(fields) => fields,
+        _ => unreachable!(),
+    }
+}
+
+fn short(value: &Value) -> String {
+    match value {
+        Value::Array(items) if items.len() > 8 => format!(
+            "[{}, ... {} items]",
+            items[..8]
+                .iter()
+                .map(Value::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+            items.len()
+        ),
+        value => value.to_string(),
+    }
+}
+
+/// the encoded reads are compared first, the fields are only decoded to report a difference
+fn compare_reads(cli: &Cli, summary: &mut DiffSummary, encoded_a: &[u8], encoded_b: &[u8]) {
+    if encoded_a == encoded_b {
+        summary.identical += 1;
+        return;
+    }
+    let decode = |encoded| {
+        let (read, _nbytes): (ReadInfo, usize) =
+            bincode::decode_from_slice(encoded, get_bincode_cfg()).unwrap();
+        fields(&read)
+    };
+    let (read_a, read_b) = (decode(encoded_a), decode(encoded_b));
+    summary.different += 1;
+    for (field, value_a) in &read_a {
+        let value_b = read_b.get(field).unwrap_or(&Value::Null);
+        if value_a == value_b {
+            continue;
+        }
+        *summary.fields.entry(field.clone()).or_default() += 1;
+        summary.report(
+            cli.max_report,
+            format!(
+                "read {} field {}: {} vs {}",
+                read_a["name"],
+                field,
+                short(value_a),
+                short(value_b)
+            ),
+        );
+    }
+}
+
+fn diff_reads(cli: &Cli, summary: &mut DiffSummary) {
+    let (a, b) = (open(&cli.a), open(&cli.b));
+    for reader in [&a, &b] {
+        check_reads_format(reader.section(READS_FORMAT_SECTION).as_deref());
+    }
+    match cli.order {
+        DiffOrder::Sequence => {
+            let mut reads_a = reads(&a);
+            let mut reads_b = reads(&b);
+            loop {
+                match (reads_a.next(), reads_b.next()) {
+                    (None, None) => break,
+                    (Some((read_a, _)), None) => {
+                        summary.num_a += 1;
+                        summary.missing += 1;
+                        summary.report(cli.max_report, format!("read {}: only in a", read_a.name));
+                    }
+                    (None, Some((read_b, _))) => {
+                        summary.num_b += 1;
+                        summary.extra += 1;
+                        summary.report(cli.max_report, format!("read {}: only in b", read_b.name));
+                    }
+                    (Some((_, encoded_a)), Some((_, encoded_b))) => {
+                        summary.num_a += 1;
+                        summary.num_b += 1;
+                        compare_reads(cli, summary, &encoded_a, &encoded_b);
+                    }
+                }
+            }
+        }
+        DiffOrder::Multiset => {
+            // (name, hash of the encoded read) -> indices of the reads in a,
+            // so only the hashes are held in memory
+            let mut only_a: HashMap<(String, RecordHash), Vec<u64>> = HashMap::new();
+            for (idx, (read, encoded)) in reads(&a).enumerate() {
+                summary.num_a += 1;
+                only_a
+                    .entry((read.name, record_hash(&encoded)))
+                    .or_default()
+                    .push(idx as u64);
+            }
+            let mut unmatched_b = vec![];
+            for (idx, (read, encoded)) in reads(&b).enumerate() {
+                summary.num_b += 1;
+                let matched = only_a
+                    .get_mut(&(read.name, record_hash(&encoded)))
+                    .and_then(|indices| indices.pop());
+                match matched {
+                    Some(_) => summary.identical += 1,
+                    None => unmatched_b.push(idx as u64),
+                }
+            }
+
+            // the reads without an identical one are paired by name, only they are read again
+            let mut left_a: HashMap<String, Vec<u64>> = HashMap::new();
+            for ((name, _), indices) in only_a {
+                left_a.entry(name).or_default().extend(indices);
+            }
+            left_a
+                .values_mut()
+                .for_each(|indices| indices.sort_by(|x, y| y.cmp(x)));
+            let mut encoded_a = encoded_reads(&a, left_a.values().flatten().copied().collect());
+            let mut encoded_b = encoded_reads(&b, unmatched_b.iter().copied().collect());
+            for idx_b in unmatched_b {
+                let (read_b, encoded_b) = encoded_b.remove(&idx_b).unwrap();
+                match left_a
+                    .get_mut(&read_b.name)
+                    .and_then(|indices| indices.pop())
+                {
+                    Some(idx_a) => {
+                        let (_, encoded_a) = encoded_a.remove(&idx_a).unwrap();
+                        compare_reads(cli, summary, &encoded_a, &encoded_b);
+                    }
+                    None => {
+                        summary.extra += 1;
+                        summary.report(cli.max_report, format!("read {}: not in a", read_b.name));
+                    }
+                }
+            }
+            let mut missing = left_a
+                .into_iter()
+                .flat_map(|(name, indices)| std::iter::repeat_n(name, indices.len()))
+                .collect::<Vec<_>>();
+            missing.sort();
+            for name in missing {
+                summary.missing += 1;
+                summary.report(cli.max_report, format!("read {}: not in b", name));
+            }
+        }
+    }
+}
+
+/// the reads of a file at the indices, in the order of all reads
+fn encoded_reads(
+    reader: &GasFileReader,
+    indices: HashSet<u64>,
+) -> HashMap<u64, (ReadInfo, Vec<u8>)> {
+    if indices.is_empty() {
+        return HashMap::new();
+    }
+    reads(reader)
+        .enumerate()
+        .map(|(idx, read)| (idx as u64, read))
+        .filter(|(idx, _)| indices.contains(idx))
+        .collect()
+}
+
+/// compares the files, the differences are reported in the summary
+fn diff(cli: &Cli) -> DiffSummary {
+    let mut summary = DiffSummary::default();
+    match cli.mode {
+        DiffMode::Bytes => diff_bytes(cli, &mut summary),
+        DiffMode::Reads => diff_reads(cli, &mut summary),
+    }
+    summary
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let summary = diff(&cli);
+    for line in &summary.reports {
+        println!("{}", line);
+    }
+    if summary.reported > cli.max_report {
+        println!("... {} more", summary.reported - cli.max_report);
+    }
+    let unit = match cli.mode {
+        DiffMode::Bytes => "records",
+        DiffMode::Reads => "reads",
+    };
+    println!(
+        "a: {} {unit}, b: {} {unit}, identical: {}, different: {}, only in a: {}, only in b: {}",
+        summary.num_a,
+        summary.num_b,
+        summary.identical,
+        summary.different,
+        summary.missing,
+        summary.extra,
+    );
+    if !summary.fields.is_empty() {
+        println!(
+            "different fields: {}",
+            summary
+                .fields
+                .iter()
+                .map(|(field, count)| format!("{}:{}", field, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    if summary.has_diff() {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{num::NonZero, path::Path};
+
+    use clap::Parser;
+    use gas::{
+        io::v1::{GasFileWriter, GasRecord},
+        reads::{BatchReads, READS_FORMAT_SECTION, ReadInfo, reads_format_section},
+    };
+    use tempfile::TempDir;
+
+    use super::{Cli, DiffSummary, diff};
+
+    fn write_records(path: &Path, records: Vec<GasRecord>) {
+        let (writer, sender) = GasFileWriter::new_writer(path, NonZero::new(1).unwrap());
+        writer.add_section(READS_FORMAT_SECTION, reads_format_section());
+        writer.start_write_worker();
+        for record in records {
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+    }
+
+    /// one record per batch of (name, rq)
+    fn write_reads(path: &Path, batches: &[&[(&str, f32)]]) {
+        let records = batches
+            .iter()
+            .map(|batch| {
+                BatchReads(
+                    batch
+                        .iter()
+                        .map(|&(name, rq)| ReadInfo {
+                            rq: Some(rq),
+                            ..ReadInfo::new_fa_record(name.to_string(), "ACGT".to_string())
+                        })
+                        .collect(),
+                )
+                .to_gas_record(None)
+            })
+            .collect();
+        write_records(path, records);
+    }
+
+    fn run(dir: &TempDir, args: &[&str]) -> DiffSummary {
+        let (a, b) = (dir.path().join("a.gas"), dir.path().join("b.gas"));
+        let cli = Cli::try_parse_from(
+            ["gas-diff", a.to_str().unwrap(), b.to_str().unwrap()]
+                .into_iter()
+                .chain(args.iter().copied()),
+        )
+        .unwrap();
+        diff(&cli)
+    }
+
+    fn counts(summary: &DiffSummary) -> [u64; 6] {
+        [
+            summary.num_a,
+            summary.num_b,
+            summary.identical,
+            summary.different,
+            summary.missing,
+            summary.extra,
+        ]
+    }
+
+    #[test]
+    fn test_diff_bytes() {
+        let dir = TempDir::new().unwrap();
+        let write = |name: &str, values: &[u8]| {
+            let records = values.iter().map(|&v| GasRecord::new(vec![v, v])).collect();
+            write_records(&dir.path().join(name), records);
+        };
+        write("a.gas", &[1, 2, 2]);
+        write("b.gas", &[2, 1, 3, 4]);
+
+        let summary = run(&dir, &["--mode", "bytes", "--order", "sequence"]);
+        assert_eq!(counts(&summary), [3, 4, 0, 3, 0, 1]);
+        assert_eq!(
+            summary.reports[0],
+            "record 0: len 2 vs 2, first difference at byte 0"
+        );
+        assert_eq!(summary.reports[3], "record 3: only in b");
+
+        let summary = run(&dir, &["--mode", "bytes"]);
+        assert_eq!(counts(&summary), [3, 4, 2, 0, 1, 2]);
+        assert_eq!(
+            summary.reports,
+            vec![
+                "record 2 of b: not in a, len 2",
+                "record 3 of b: not in a, len 2",
+                "record 1 of a: not in b",
+            ]
+        );
+        assert!(summary.has_diff());
+
+        write("b.gas", &[2, 1, 2]);
+        let summary = run(&dir, &["--mode", "bytes"]);
+        assert_eq!(counts(&summary), [3, 3, 3, 0, 0, 0]);
+        assert!(!summary.has_diff());
+    }
+
+    #[test]
+    fn test_diff_reads_multiset() {
+        let dir = TempDir::new().unwrap();
+        write_reads(
+            &dir.path().join("a.gas"),
+            &[&[("r0", 0.5), ("r1", 0.5)], &[("r1", 0.75), ("r2", 0.5)]],
+        );
+        // reordered and rebatched. r1 is duplicated with different fields, r0 differs
+        write_reads(
+            &dir.path().join("b.gas"),
+            &[&[("r1", 0.75)], &[("r3", 0.5), ("r0", 0.25), ("r1", 0.5)]],
+        );
+
+        let summary = run(&dir, &[]);
+        assert_eq!(counts(&summary), [4, 4, 2, 1, 1, 1]);
+        assert_eq!(summary.fields.get("rq"), Some(&1));
+        assert_eq!(
+            summary.reports,
+            vec![
+                "read r3: not in a",
+                "read \"r0\" field rq: 0.5 vs 0.25",
+                "read r2: not in b",
+            ]
+        );
+
+        // all the differences are counted, only the first ones are reported
+        let summary = run(&dir, &["--max-report", "1"]);
+        assert_eq!(summary.reports, vec!["read r3: not in a"]);
+        assert_eq!(summary.reported, 3);
+    }
+
+    #[test]
+    fn test_diff_reads_sequence() {
+        let dir = TempDir::new().unwrap();
+        write_reads(&dir.path().join("a.gas"), &[&[("r0", 0.5), ("r1", 0.5)]]);
+        write_reads(
+            &dir.path().join("b.gas"),
+            &[&[("r0", 0.5)], &[("r2", 0.5)], &[("r3", 0.5)]],
+        );
+
+        let summary = run(&dir, &["--order", "sequence"]);
+        assert_eq!(counts(&summary), [2, 3, 1, 1, 0, 1]);
+        assert_eq!(summary.fields.get("name"), Some(&1));
+        assert_eq!(
+            summary.reports,
+            vec![
+                "read \"r1\" field name: \"r1\" vs \"r2\"",
+                "read r3: only in b"
+            ]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    io::{self, BufWriter, Write},
+    num::NonZero,
+};
+
+use clap::{Parser, ValueEnum};
+use gas::{
+    io::v1::{GasFileReader, get_bincode_cfg},
+    reads::{BatchReads, READS_FORMAT_SECTION, check_reads_format},
+};
+use serde_json::{Map, Value, json};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    /// the raw bytes of each record as hex
+    Raw,
+    /// BatchReads written by bam-gas-cvt, one line per read
+    Reads,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "print the records of a gas file as JSON Lines", long_about = None)]
+pub struct Cli {
+    pub in_path: String,
+
+    #[arg(long = "format", value_enum, default_value_t = DumpFormat::Raw)]
+    pub format: DumpFormat,
+
+    #[arg(long = "head", help = "print at most N records")]
+    pub head: Option<usize>,
+
+    #[arg(long = "skip", default_value_t = 0, help = "skip the first N records")]
+    pub skip: usize,
+
+    #[arg(
+        long = "index",
+        value_delimiter = ',',
+        help = "i,j,k. only print the records with these indices. --skip and --head are ignored"
+    )]
+    pub index: Option<Vec<u64>>,
+
+    #[arg(
+        long = "fields",
+        value_delimiter = ',',
+        help = "name,seq,rq... only print these fields of a read. only valid for --format reads"
+    )]
+    pub fields: Option<Vec<String>>,
+
+    #[arg(long = "full", help = "do not truncate long arrays")]
+    pub full: bool,
+
+    #[arg(long = "max-array-len", default_value_t = 16)]
+    pub max_array_len: usize,
+}
+
+/// keep the first `max_len` items, the rest is replaced by a "... N more" marker
+fn truncate_arrays(value: &mut Value, max_len: usize) {
+    match value {
+        Value::Array(items) if items.len() > max_len => {
+            let more = items.len() - max_len;
+            items.truncate(max_len);
+            items.push(Value::String(format!("... {} more", more)));
+        }
+        Value::Object(map) => map.values_mut().for_each(|v| truncate_arrays(v, max_len)),
+        _ => {}
+    }
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn record_lines(cli: &Cli, record_idx: u64, data: &[u8]) -> Vec<Value> {
+    match cli.format {
+        DumpFormat::Raw => {
+            let shown = if cli.full {
+                data
+            } else {
+                &data[..data.len().min(cli.max_array_len)]
+            };
+            let mut hex = hex(shown);
+            if shown.len() < data.len() {
+                hex.push_str("...");
+            }
+            vec![json!({"record": record_idx, "len": data.len(), "data": hex})]
+        }
+        DumpFormat::Reads => {
+            let (batch, _nbytes): (BatchReads, usize) =
+                bincode::decode_from_slice(data, get_bincode_cfg()).unwrap_or_else(|err| {
+                    panic!("record {} is not a BatchReads. {}", record_idx, err)
+                });
+            batch
+                .iter()
+                .map(|read| {
+                    let Value::Object(fields) = serde_json::to_value(read).unwrap() else {
+                        unreachable!()
+                    };
+                    let mut line = Map::new();
+                    line.insert("record".to_string(), json!(record_idx));
+                    for (name, mut value) in fields {
+                        if cli
+                            .fields
+                            .as_ref()
+                            .is_some_and(|selected| !selected.contains(&name))
+                        {
+                            continue;
+                        }
+                        if !cli.full {
+                            truncate_arrays(&mut value, cli.max_array_len);
+                        }
+                        line.insert(name, value);
+                    }
+                    Value::Object(line)
+                })
+                .collect()
+        }
+    }
+}
+
+fn dump<W: Write>(cli: &Cli, out: &mut W) -> io::Result<()> {
+    let (reader, _recv) = GasFileReader::new_reader(&cli.in_path, NonZero::new(1).unwrap());
+    if cli.format == DumpFormat::Reads {
+        check_reads_format(reader.section(READS_FORMAT_SECTION).as_deref());
+    }
+    let records: Box<dyn Iterator<Item = (u64, Vec<u8>)>> = match &cli.index {
+        Some(indices) => Box::new(indices.iter().map(|&idx| {
+            let data = reader.get(idx).unwrap_or_else(|| {
+                panic!(
+                    "record index {} out of range, the file has {} records",
+                    idx,
+                    reader.num_records()
+                )
+            });
+            (idx, data)
+        })),
+        None => Box::new(
+            reader
+                .records()
+                .enumerate()
+                .map(|(idx, data)| (idx as u64, data))
+                .skip(cli.skip)
+                .take(cli.head.unwrap_or(usize::MAX)),
+        ),
+    };
+
+    for (record_idx, data) in records {
+        for line in record_lines(cli, record_idx, &data) {
+            serde_json::to_writer(&mut *out, &line)?;
+            out.write_all(b"\n")?;
+        }
+    }
+    out.flush()
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let mut out = BufWriter::new(io::stdout().lock());
+    match dump(&cli, &mut out) {
+        Ok(()) => {}
+        // e.g. gas-dump in.gas | head
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(err) => panic!("write error. {}", err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{num::NonZero, path::Path};
+
+    use clap::Parser;
+    use gas::{
+        io::v1::{GasFileWriter, GasRecord},
+        reads::{BatchReads, READS_FORMAT_SECTION, ReadInfo, reads_format_section},
+    };
+    use serde_json::{Value, json};
+    use tempfile::TempDir;
+
+    use super::{Cli, dump, truncate_arrays};
+
+    fn write_records(path: &Path, records: Vec<GasRecord>, reads: bool) {
+        let (writer, sender) = GasFileWriter::new_writer(path, NonZero::new(1).unwrap());
+        if reads {
+            writer.add_section(READS_FORMAT_SECTION, reads_format_section());
+        }
+        writer.start_write_worker();
+        for record in records {
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+    }
+
+    /// the json lines gas-dump prints with the args
+    fn run(path: &Path, args: &[&str]) -> Vec<Value> {
+        let cli = Cli::try_parse_from(
+            ["gas-dump", path.to_str().unwrap()]
+                .into_iter()
+                .chain(args.iter().copied()),
+        )
+        .unwrap();
+        let mut out = vec![];
+        dump(&cli, &mut out).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_truncate_arrays() {
+        let mut value = json!({"a": [1, 2, 3, 4, 5], "b": {"c": [1, 2, 3]}, "d": "abcdef"});
+        truncate_arrays(&mut value, 3);
+        assert_eq!(
+            value,
+            json!({"a": [1, 2, 3, "... 2 more"], "b": {"c": [1, 2, 3]}, "d": "abcdef"})
+        );
+    }
+
+    #[test]
+    fn test_dump_raw() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("in.gas");
+        write_records(
+            &path,
+            (0_u8..5).map(|i| GasRecord::new(vec![i; 20])).collect(),
+            false,
+        );
+
+        let lines = run(&path, &[]);
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[1],
+            json!({"record": 1, "len": 20, "data": format!("{}...", "01".repeat(16))})
+        );
+        let lines = run(&path, &["--full"]);
+        assert_eq!(lines[1]["data"], json!("01".repeat(20)));
+        let lines = run(&path, &["--max-array-len", "2"]);
+        assert_eq!(lines[1]["data"], json!("0101..."));
+
+        let records = |lines: Vec<Value>| {
+            lines
+                .iter()
+                .map(|line| line["record"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            records(run(&path, &["--skip", "1", "--head", "2"])),
+            vec![1, 2]
+        );
+        assert_eq!(
+            records(run(&path, &["--skip", "4", "--head", "2"])),
+            vec![4]
+        );
+        // --index ignores --skip and --head, the records come in the given order
+        assert_eq!(
+            records(run(
+                &path,
+                &["--index", "4,0", "--skip", "1", "--head", "1"]
+            )),
+            vec![4, 0]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_dump_index_out_of_range() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("in.gas");
+        write_records(&path, vec![GasRecord::new(vec![0])], false);
+        run(&path, &["--index", "1"]);
+    }
+
+    #[test]
+    fn test_dump_reads() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("in.gas");
+        let batch = BatchReads(vec![
+            ReadInfo {
+                rq: Some(0.5),
+                ..ReadInfo::new_fa_record("r0".to_string(), "ACGT".to_string())
+            },
+            ReadInfo {
+                be: Some((0..20).collect()),
+                ..ReadInfo::new_fa_record("r1".to_string(), "AC".to_string())
+            },
+        ]);
+        write_records(&path, vec![batch.to_gas_record(None)], true);
+
+        let lines = run(&path, &["--format", "reads", "--fields", "name,rq"]);
+        assert_eq!(
+            lines,
+            vec![
+                json!({"record": 0, "name": "r0", "rq": 0.5}),
+                json!({"record": 0, "name": "r1", "rq": null}),
+            ]
+        );
+
+        let lines = run(&path, &["--format", "reads", "--max-array-len", "3"]);
+        assert_eq!(lines[1]["seq"], json!("AC"));
+        assert_eq!(lines[1]["be"], json!([0, 1, 2, "... 17 more"]));
+        let lines = run(&path, &["--format", "reads", "--full"]);
+        assert_eq!(lines[1]["be"].as_array().unwrap().len(), 20);
+    }
+
+    #[test]
+    #[should_panic(expected = "older version")]
+    fn test_dump_reads_format() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("in.gas");
+        write_records(&path, vec![GasRecord::new(vec![0])], false);
+        run(&path, &["--format", "reads"]);
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::HashSet, num::NonZero, sync::Arc};
+
+use clap::{Parser, ValueEnum};
+use gas::{
+    io::v1::{GasFileReader, GasFileWriter, GasRecord, GasRecordIter, get_bincode_cfg},
+    reads::{
+        BAM_HEADER_SECTION, READS_FORMAT_SECTION, TAG_SELECTION_SECTION, TagSelection,
+        merge_bam_headers,
+    },
+};
+use gskits::pbar::{DEFAULT_INTERVAL, get_spin_pb};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum MergeMode {
+    /// all records of the first file, then the second ...
+    Concat,
+    /// one record from each file in turn
+    Interleave,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "merge gas files into one. when all inputs share a codec the stored payloads are copied as they are, otherwise they are re-encoded like the first input",
+    long_about = None
+)]
+pub struct Cli {
+    #[arg(required = true, num_args = 1..)]
+    pub in_paths: Vec<String>,
+
+    #[arg(short = 'o', long = "out")]
+    pub out_path: String,
+
+    #[arg(long = "mode", value_enum, default_value_t = MergeMode::Concat)]
+    pub mode: MergeMode,
+
+    #[arg(
+        long = "dedup",
+        help = "drop a record if all its keys are already written by an earlier record. the inputs must be written with keys"
+    )]
+    pub dedup: bool,
+}
+
+struct MergeSource {
+    reader: Arc<GasFileReader>,
+    records: GasRecordIter,
+}
+
+impl MergeSource {
+    fn new(reader: Arc<GasFileReader>, stored: bool) -> Self {
+        let records = reader.gas_records();
+        Self {
+            records: if stored { records.stored() } else { records },
+            reader,
+        }
+    }
+}
+
+/// merges the inputs into the output, returns the number of records dropped by --dedup
+fn merge(cli: &Cli) -> u64 {
+    let readers = cli
+        .in_paths
+        .iter()
+        .map(|p| GasFileReader::new_reader(p, NonZero::new(1).unwrap()).0)
+        .collect::<Vec<_>>();
+    // the output is stored like the first input, the payloads are only decoded if another input differs
+    let codec = readers[0].codec();
+    let stored = readers.iter().all(|reader| reader.codec() == codec);
+    let mut sources = readers
+        .into_iter()
+        .map(|reader| MergeSource::new(reader, stored))
+        .collect::<Vec<_>>();
+    if cli.dedup {
+        for source in &sources {
+            assert!(
+                source.reader.key_index().is_some(),
+                "--dedup needs keys, but {:?} is written without keys",
+                source.reader.path().unwrap()
+            );
+        }
+    }
+
+    let (writer, sender) = GasFileWriter::new_writer(&cli.out_path, NonZero::new(1).unwrap());
+    writer.set_codec(codec);
+    writer.start_write_worker();
+
+    // the bam headers and tag selections of the inputs are merged, other sections added by users
+    // are taken from the first input that has them
+    let mut section_names = vec![];
+    for source in &sources {
+        for name in source.reader.user_sections() {
+            if !section_names.contains(&name) {
+                section_names.push(name);
+            }
+        }
+    }
+    for name in section_names {
+        let sections = sources
+            .iter()
+            .filter_map(|source| source.reader.section(name))
+            .collect::<Vec<_>>();
+        let section = match name {
+            BAM_HEADER_SECTION => {
+                merge_bam_headers(&sections.iter().map(Vec::as_slice).collect::<Vec<_>>())
+            }
+            TAG_SELECTION_SECTION => {
+                let tags = sections
+                    .iter()
+                    .map(|data| {
+                        let (tags, _nbytes): (TagSelection, usize) =
+                            bincode::decode_from_slice(data, get_bincode_cfg()).unwrap();
+                        tags
+                    })
+                    .reduce(|merged, tags| merged.union(&tags))
+                    .unwrap();
+                bincode::encode_to_vec(&tags, get_bincode_cfg()).unwrap()
+            }
+            READS_FORMAT_SECTION => {
+                assert!(
+                    sections.len() == sources.len() && sections.iter().all(|s| s == &sections[0]),
+                    "the reads of the inputs are written in different formats, repack them first"
+                );
+                sections[0].clone()
+            }
+            _ => sections[0].clone(),
+        };
+        writer.add_section(name, section);
+    }
+
+    let pb = get_spin_pb(format!("writing {}", cli.out_path), DEFAULT_INTERVAL);
+    let mut seen_keys = HashSet::new();
+    let mut num_dropped = 0;
+    let mut send = |record: GasRecord| {
+        if cli.dedup && !record.keys.is_empty() {
+            let mut new_key = false;
+            for key in &record.keys {
+                new_key |= seen_keys.insert(key.clone());
+            }
+            if !new_key {
+                num_dropped += 1;
+                return;
+            }
+        }
+        pb.inc(1);
+        sender.send(record).unwrap();
+    };
+
+    match cli.mode {
+        MergeMode::Concat => {
+            for source in sources.iter_mut() {
+                for record in source.records.by_ref() {
+                    send(record);
+                }
+            }
+        }
+        MergeMode::Interleave => loop {
+            let mut exhausted = true;
+            for source in sources.iter_mut() {
+                if let Some(record) = source.records.next() {
+                    exhausted = false;
+                    send(record);
+                }
+            }
+            if exhausted {
+                break;
+            }
+        },
+    }
+    drop(sender);
+    writer.wait_for_write_done();
+    pb.finish();
+    num_dropped
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let num_dropped = merge(&cli);
+    if cli.dedup {
+        println!("dropped {} duplicated records", num_dropped);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{num::NonZero, path::Path};
+
+    use clap::Parser;
+    use gas::{
+        io::{
+            codec::{Checksum, Compression, RecordCodec},
+            v1::{GasFileReader, GasFileWriter, GasRecord, get_bincode_cfg},
+        },
+        reads::{
+            BAM_HEADER_SECTION, READS_FORMAT_SECTION, TAG_SELECTION_SECTION, TagSelection,
+            merge_bam_headers, reads_format_section,
+        },
+    };
+    use tempfile::TempDir;
+
+    use super::{Cli, merge};
+
+    /// records are (value, keys)
+    fn write_input(
+        path: &Path,
+        codec: RecordCodec,
+        sections: Vec<(&str, Vec<u8>)>,
+        records: &[(u32, &[&str])],
+    ) {
+        let (writer, sender) = GasFileWriter::new_writer(path, NonZero::new(1).unwrap());
+        writer.set_codec(codec);
+        for (name, data) in sections {
+            writer.add_section(name, data);
+        }
+        writer.start_write_worker();
+        for &(value, keys) in records {
+            let mut record =
+                GasRecord::new(bincode::encode_to_vec(value, get_bincode_cfg()).unwrap());
+            for key in keys {
+                record = record.with_key(*key);
+            }
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+    }
+
+    /// the values and keys of the records
+    type Records = Vec<(u32, Vec<Vec<u8>>)>;
+
+    fn read_output(path: &Path) -> Records {
+        let (reader, _recv) = GasFileReader::new_reader(path, NonZero::new(1).unwrap());
+        reader
+            .gas_records()
+            .map(|record| {
+                let (value, _nbytes): (u32, usize) =
+                    bincode::decode_from_slice(&record.data, get_bincode_cfg()).unwrap();
+                (value, record.keys)
+            })
+            .collect()
+    }
+
+    fn run(dir: &TempDir, args: &[&str]) -> (u64, Records) {
+        let out = dir.path().join("out.gas");
+        let a = dir.path().join("a.gas");
+        let b = dir.path().join("b.gas");
+        let cli = Cli::try_parse_from(
+            [
+                "gas-merge",
+                a.to_str().unwrap(),
+                b.to_str().unwrap(),
+                "-o",
+                out.to_str().unwrap(),
+            ]
+            .into_iter()
+            .chain(args.iter().copied()),
+        )
+        .unwrap();
+        let num_dropped = merge(&cli);
+        (num_dropped, read_output(&out))
+    }
+
+    fn write_inputs(dir: &TempDir, codecs: [RecordCodec; 2]) {
+        write_input(
+            &dir.path().join("a.gas"),
+            codecs[0],
+            vec![],
+            &[(0, &["r0", "z0"]), (1, &["r1"]), (2, &["r2"])],
+        );
+        write_input(
+            &dir.path().join("b.gas"),
+            codecs[1],
+            vec![],
+            &[(10, &["r1"]), (11, &["r11", "z0"])],
+        );
+    }
+
+    #[test]
+    fn test_merge_modes() {
+        let dir = TempDir::new().unwrap();
+        write_inputs(&dir, [RecordCodec::default(); 2]);
+
+        let (num_dropped, records) = run(&dir, &[]);
+        assert_eq!(num_dropped, 0);
+        assert_eq!(
+            records.iter().map(|(value, _)| *value).collect::<Vec<_>>(),
+            vec![0, 1, 2, 10, 11]
+        );
+        assert_eq!(records[0].1, vec![b"r0".to_vec(), b"z0".to_vec()]);
+
+        let (_, records) = run(&dir, &["--mode", "interleave"]);
+        assert_eq!(
+            records.iter().map(|(value, _)| *value).collect::<Vec<_>>(),
+            vec![0, 10, 1, 11, 2]
+        );
+    }
+
+    #[test]
+    fn test_merge_dedup() {
+        let dir = TempDir::new().unwrap();
+        write_inputs(&dir, [RecordCodec::default(); 2]);
+
+        // 10 only has the key r1 of 1, 11 still has the new key r11
+        let (num_dropped, records) = run(&dir, &["--dedup"]);
+        assert_eq!(num_dropped, 1);
+        assert_eq!(
+            records.iter().map(|(value, _)| *value).collect::<Vec<_>>(),
+            vec![0, 1, 2, 11]
+        );
+
+        // in turn, 10 comes before 1 and the later one is dropped
+        let (num_dropped, records) = run(&dir, &["--dedup", "--mode", "interleave"]);
+        assert_eq!(num_dropped, 1);
+        assert_eq!(
+            records.iter().map(|(value, _)| *value).collect::<Vec<_>>(),
+            vec![0, 10, 11, 2]
+        );
+    }
+
+    #[test]
+    fn test_merge_codecs() {
+        let dir = TempDir::new().unwrap();
+        let zstd = RecordCodec {
+            compression: Compression::Zstd { level: 3 },
+            checksum: Checksum::Crc32,
+        };
+        // the same codec copies the stored payloads, another one re-encodes them
+        for codecs in [[zstd, zstd], [zstd, RecordCodec::default()]] {
+            write_inputs(&dir, codecs);
+            let (_, records) = run(&dir, &[]);
+            assert_eq!(
+                records.iter().map(|(value, _)| *value).collect::<Vec<_>>(),
+                vec![0, 1, 2, 10, 11]
+            );
+            let (reader, _recv) =
+                GasFileReader::new_reader(dir.path().join("out.gas"), NonZero::new(1).unwrap());
+            assert_eq!(reader.codec(), zstd);
+        }
+    }
+
+    #[test]
+    fn test_merge_sections() {
+        let dir = TempDir::new().unwrap();
+        let first = "@HD\tVN:1.6\n@RG\tID:a\tSM:x\n@PG\tID:ccs\tPN:ccs\n";
+        let second = "@HD\tVN:1.6\n@RG\tID:b\tSM:y\n@PG\tID:ccs\tPN:ccs\tVN:2\n";
+        let tags = |tags: &[&str]| {
+            let tags = TagSelection::none().with_tags(tags.iter().copied());
+            bincode::encode_to_vec(&tags, get_bincode_cfg()).unwrap()
+        };
+        write_input(
+            &dir.path().join("a.gas"),
+            RecordCodec::default(),
+            vec![
+                (BAM_HEADER_SECTION, first.as_bytes().to_vec()),
+                (TAG_SELECTION_SECTION, tags(&["np"])),
+                (READS_FORMAT_SECTION, reads_format_section()),
+                ("user.note", b"a".to_vec()),
+            ],
+            &[(0, &[])],
+        );
+        write_input(
+            &dir.path().join("b.gas"),
+            RecordCodec::default(),
+            vec![
+                (BAM_HEADER_SECTION, second.as_bytes().to_vec()),
+                (TAG_SELECTION_SECTION, tags(&["rq"])),
+                (READS_FORMAT_SECTION, reads_format_section()),
+                ("user.note", b"b".to_vec()),
+                ("user.other", b"x".to_vec()),
+            ],
+            &[(1, &[])],
+        );
+        run(&dir, &[]);
+
+        let (reader, _recv) =
+            GasFileReader::new_reader(dir.path().join("out.gas"), NonZero::new(1).unwrap());
+        assert_eq!(
+            reader.section(BAM_HEADER_SECTION),
+            Some(merge_bam_headers(&[first.as_bytes(), second.as_bytes()]))
+        );
+        assert_eq!(
+            reader.section(TAG_SELECTION_SECTION),
+            Some(tags(&["np", "rq"]))
+        );
+        assert_eq!(
+            reader.section(READS_FORMAT_SECTION),
+            Some(reads_format_section())
+        );
+        // other sections are taken from the first input that has them
+        assert_eq!(reader.section("user.note"), Some(b"a".to_vec()));
+        assert_eq!(reader.section("user.other"), Some(b"x".to_vec()));
+    }
+
+    #[test]
+    #[should_panic(expected = "different formats")]
+    fn test_merge_reads_formats() {
+        let dir = TempDir::new().unwrap();
+        write_input(
+            &dir.path().join("a.gas"),
+            RecordCodec::default(),
+            vec![(READS_FORMAT_SECTION, reads_format_section())],
+            &[(0, &[])],
+        );
+        write_input(
+            &dir.path().join("b.gas"),
+            RecordCodec::default(),
+            vec![],
+            &[(1, &[])],
+        );
+        run(&dir, &[]);
+    }
+}
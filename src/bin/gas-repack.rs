@@ -0,0 +1,151 @@
+use std::num::NonZero;
+
+use clap::{ArgGroup, Parser, ValueEnum};
+use gas::{
+    io::{
+        codec::{Checksum, Compression, RecordCodec},
+        v1::{GasFileReader, GasFileWriter},
+    },
+    reads::{BatchReads, READS_FORMAT_SECTION, ReadInfo, check_reads_format},
+};
+use gskits::pbar::{DEFAULT_INTERVAL, get_spin_pb};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CompressionArg {
+    None,
+    Zstd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChecksumArg {
+    None,
+    Crc32,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "rebatch the BatchReads of a gas file and write a fresh file, optionally with another compression/checksum",
+    long_about = None
+)]
+#[command(group(ArgGroup::new("batching").required(true).multiple(true).args(["batch_size", "max_batch_bytes"])))]
+pub struct Cli {
+    pub in_path: String,
+
+    #[arg(short = 'o', long = "out")]
+    pub out_path: String,
+
+    #[arg(long = "batch-size", help = "number of reads per record")]
+    pub batch_size: Option<NonZero<usize>>,
+
+    #[arg(
+        long = "max-batch-bytes",
+        help = "start a new record before the encoded reads exceed N bytes. a larger read gets its own record"
+    )]
+    pub max_batch_bytes: Option<NonZero<usize>>,
+
+    #[arg(long = "compression", value_enum, help = "default: same as the input")]
+    pub compression: Option<CompressionArg>,
+
+    #[arg(long = "zstd-level", default_value_t = 3)]
+    pub zstd_level: i32,
+
+    #[arg(long = "checksum", value_enum, help = "default: same as the input")]
+    pub checksum: Option<ChecksumArg>,
+
+    #[arg(
+        long = "key",
+        value_parser = ["name", "ch"],
+        help = "the key the output is indexed by. default: the key of the input, if any"
+    )]
+    pub key: Option<String>,
+}
+
+impl Cli {
+    fn codec(&self, input: RecordCodec) -> RecordCodec {
+        RecordCodec {
+            compression: match self.compression {
+                None => input.compression,
+                Some(CompressionArg::None) => Compression::None,
+                Some(CompressionArg::Zstd) => Compression::Zstd {
+                    level: self.zstd_level,
+                },
+            },
+            checksum: match self.checksum {
+                None => input.checksum,
+                Some(ChecksumArg::None) => Checksum::None,
+                Some(ChecksumArg::Crc32) => Checksum::Crc32,
+            },
+        }
+    }
+}
+
+/// name/ch, judged by whether the file bloom filter holds the key of the first read
+fn input_key_kind(reader: &GasFileReader, read: &ReadInfo) -> Option<&'static str> {
+    reader.key_blooms()?;
+    ["name", "ch"].into_iter().find(|kind| {
+        read.key(kind)
+            .is_some_and(|key| reader.may_contain_key(&key))
+    })
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let (reader, _recv) = GasFileReader::new_reader(&cli.in_path, NonZero::new(1).unwrap());
+    check_reads_format(reader.section(READS_FORMAT_SECTION).as_deref());
+
+    let (writer, sender) = GasFileWriter::new_writer(&cli.out_path, NonZero::new(1).unwrap());
+    writer.set_codec(cli.codec(reader.codec()));
+    for name in reader.user_sections() {
+        writer.add_section(name, reader.section(name).unwrap());
+    }
+    writer.start_write_worker();
+
+    let pb = get_spin_pb(format!("repacking {}", cli.in_path), DEFAULT_INTERVAL);
+    let batch_size = cli.batch_size.map_or(usize::MAX, NonZero::get);
+    let max_batch_bytes = cli.max_batch_bytes.map_or(usize::MAX, NonZero::get);
+    let mut key: Option<Option<String>> = cli.key.clone().map(Some);
+    let mut batch = BatchReads(vec![]);
+    let mut batch_bytes = 0;
+    let mut num_records = 0;
+
+    // only one input record and one output batch are held at a time
+    for data in reader.records() {
+        for (read, encoded) in BatchReads::decode_iter(&data) {
+            let read_bytes = encoded.len();
+            let key = key
+                .get_or_insert_with(|| input_key_kind(&reader, &read).map(str::to_string))
+                .as_deref();
+            pb.inc(1);
+            batch.push(read);
+            if batch.len() > 1 && batch_bytes + read_bytes > max_batch_bytes {
+                let read = batch.pop().unwrap();
+                sender.send(batch.to_gas_record(key)).unwrap();
+                num_records += 1;
+                batch = BatchReads(vec![read]);
+                batch_bytes = 0;
+            }
+            batch_bytes += read_bytes;
+            if batch.len() >= batch_size {
+                sender.send(batch.to_gas_record(key)).unwrap();
+                num_records += 1;
+                batch = BatchReads(vec![]);
+                batch_bytes = 0;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        let key = key.flatten();
+        sender.send(batch.to_gas_record(key.as_deref())).unwrap();
+        num_records += 1;
+    }
+    drop(sender);
+    writer.wait_for_write_done();
+    pb.finish();
+    println!(
+        "{} reads in {} records, {} records before",
+        pb.position(),
+        num_records,
+        reader.num_records()
+    );
+}
@@ -0,0 +1,136 @@
+use std::num::NonZero;
+
+use clap::{ArgGroup, Parser};
+use gas::io::v1::{GasFileReader, GasFileWriter};
+use gskits::pbar::{DEFAULT_INTERVAL, get_spin_pb};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "reproducible random subset of a gas file. only the index and the selected records are read",
+    long_about = None
+)]
+#[command(group(ArgGroup::new("size").required(true).args(["fraction", "count"])))]
+pub struct Cli {
+    pub in_path: String,
+
+    #[arg(short = 'o', long = "out")]
+    pub out_path: String,
+
+    #[arg(
+        long = "fraction",
+        help = "keep each record with this probability, e.g. 0.01"
+    )]
+    pub fraction: Option<f64>,
+
+    #[arg(
+        long = "count",
+        help = "keep exactly N records, or all if the file has fewer"
+    )]
+    pub count: Option<u64>,
+
+    #[arg(long = "seed", default_value_t = 0)]
+    pub seed: u64,
+}
+
+// the draws are made from the raw ChaCha8 stream instead of rand's distributions,
+// so a seed selects the same records as long as rand_chacha keeps the stream stable
+
+/// uniform in [0, 1), from the top 53 bits
+fn unit(rng: &mut ChaCha8Rng) -> f64 {
+    (rng.next_u64() >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// uniform in [0, n), by the high bits of a widening multiply
+fn below(rng: &mut ChaCha8Rng, n: u64) -> u64 {
+    ((rng.next_u64() as u128 * n as u128) >> 64) as u64
+}
+
+/// record indices in ascending order, so each index block is read only once
+fn sample_indices(
+    num_records: u64,
+    fraction: Option<f64>,
+    count: Option<u64>,
+    seed: u64,
+) -> Box<dyn Iterator<Item = u64> + Send> {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    if let Some(fraction) = fraction {
+        assert!(
+            (0.0..=1.0).contains(&fraction),
+            "invalid fraction. {}. expected a value in [0, 1]",
+            fraction
+        );
+        Box::new((0..num_records).filter(move |_| unit(&mut rng) < fraction))
+    } else {
+        // selection sampling: each record is kept with probability needed / remaining
+        let mut needed = count.unwrap().min(num_records);
+        Box::new((0..num_records).filter(move |&idx| {
+            let keep = below(&mut rng, num_records - idx) < needed;
+            needed -= keep as u64;
+            keep
+        }))
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let (reader, _recv) = GasFileReader::new_reader(&cli.in_path, NonZero::new(1).unwrap());
+    let num_records = reader.num_records();
+    let indices = sample_indices(num_records, cli.fraction, cli.count, cli.seed);
+
+    let (writer, sender) = GasFileWriter::new_writer(&cli.out_path, NonZero::new(1).unwrap());
+    for name in reader.user_sections() {
+        writer.add_section(name, reader.section(name).unwrap());
+    }
+    writer.set_codec(reader.codec());
+    writer.start_write_worker();
+
+    let pb = get_spin_pb(format!("sampling {}", cli.in_path), DEFAULT_INTERVAL);
+    // the output has the codec of the input, so the payloads are copied as they are stored
+    for record in reader.select_gas_records(indices).stored() {
+        pb.inc(1);
+        sender.send(record).unwrap();
+    }
+    drop(sender);
+    writer.wait_for_write_done();
+    pb.finish();
+    println!("sampled {} of {} records", pb.position(), num_records);
+}
+
+#[cfg(test)]
+mod test {
+    use super::sample_indices;
+
+    #[test]
+    fn test_sample_indices() {
+        let sample = |fraction, count, seed| {
+            sample_indices(1_000, fraction, count, seed).collect::<Vec<_>>()
+        };
+
+        let indices = sample(None, Some(100), 7);
+        assert_eq!(indices.len(), 100);
+        assert!(indices.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(indices, sample(None, Some(100), 7));
+        assert_ne!(indices, sample(None, Some(100), 8));
+        assert_eq!(sample(None, Some(2_000), 7), (0..1_000).collect::<Vec<_>>());
+        assert!(sample(None, Some(0), 7).is_empty());
+
+        let indices = sample(Some(0.1), None, 7);
+        assert!((50..150).contains(&indices.len()));
+        assert_eq!(indices, sample(Some(0.1), None, 7));
+        assert_eq!(sample(Some(1.0), None, 7).len(), 1_000);
+        assert!(sample(Some(0.0), None, 7).is_empty());
+
+        // pinned, a seed must keep selecting the same records
+        assert_eq!(
+            sample_indices(20, None, Some(3), 0).collect::<Vec<_>>(),
+            vec![3, 9, 16]
+        );
+        assert_eq!(
+            sample_indices(20, Some(0.5), None, 0).collect::<Vec<_>>(),
+            vec![1, 3, 9, 13, 16, 17]
+        );
+    }
+}
@@ -0,0 +1,367 @@
+use std::{num::NonZero, sync::Arc};
+
+use clap::{ArgGroup, Parser, ValueEnum};
+use crossbeam::channel::Sender;
+use gas::{
+    bloom::stable_hash64,
+    io::{
+        rolling::{RollingGasFileWriter, RollingShard, manifest_path, shard_path, write_manifest},
+        v1::{GasFileReader, GasFileWriter, GasRecord, get_bincode_cfg},
+    },
+    reads::{BatchReads, READS_FORMAT_SECTION, check_reads_format},
+};
+use gskits::pbar::{DEFAULT_INTERVAL, get_spin_pb};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashKey {
+    /// the keys the records are written with, payloads are copied as they are stored. a record with several keys goes by its smallest key
+    Stored,
+    /// read name of BatchReads, records are decoded and reads of a batch may go to different files
+    Name,
+    /// zmw hole number of BatchReads, so all reads of a zmw stay together
+    Ch,
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about = "split a gas file into {prefix}.00000.gas, {prefix}.00001.gas ... and {prefix}.manifest.tsv", long_about = None)]
+#[command(group(ArgGroup::new("rule").required(true).args(["max_records", "max_payload_bytes", "hash_parts"])))]
+pub struct Cli {
+    pub in_path: String,
+
+    #[arg(short = 'o', long = "out-prefix")]
+    pub out_prefix: String,
+
+    #[arg(long = "max-records", help = "start a new file every N records")]
+    pub max_records: Option<NonZero<u64>>,
+
+    #[arg(
+        long = "max-payload-bytes",
+        help = "start a new file before the stored payload bytes exceed N. the 2M header, index and sections come on top"
+    )]
+    pub max_payload_bytes: Option<NonZero<u64>>,
+
+    #[arg(
+        long = "hash-parts",
+        help = "split into N files by the hash of --hash-key, equal keys go to the same file"
+    )]
+    pub hash_parts: Option<NonZero<usize>>,
+
+    #[arg(long = "hash-key", value_enum, default_value_t = HashKey::Stored)]
+    pub hash_key: HashKey,
+}
+
+fn part_of(key: &[u8], num_parts: usize) -> usize {
+    (stable_hash64(key, 0) % num_parts as u64) as usize
+}
+
+/// name/ch, whichever the stored keys of the batch are made of. None if the batch is written without keys
+fn stored_key_kind(batch: &BatchReads, stored_keys: &[Vec<u8>]) -> Option<&'static str> {
+    if stored_keys.is_empty() {
+        return None;
+    }
+    ["name", "ch"].into_iter().find(|kind| {
+        let mut keys = batch
+            .iter()
+            .filter_map(|read| read.key(kind))
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys == stored_keys
+    })
+}
+
+/// the records that go to each part. reads are regrouped unless the stored keys are used
+fn split_record(record: GasRecord, hash_key: HashKey, num_parts: usize) -> Vec<(usize, GasRecord)> {
+    if hash_key == HashKey::Stored {
+        let key = record
+            .keys
+            .iter()
+            .min()
+            .unwrap_or_else(|| panic!("--hash-key stored needs keys, but a record has none"));
+        return vec![(part_of(key, num_parts), record)];
+    }
+
+    let (batch, _nbytes): (BatchReads, usize) =
+        bincode::decode_from_slice(&record.data, get_bincode_cfg()).unwrap();
+    let key_kind = stored_key_kind(&batch, &record.keys);
+    let mut parts = (0..num_parts)
+        .map(|_| BatchReads(vec![]))
+        .collect::<Vec<_>>();
+    for read in batch.0 {
+        let key = match hash_key {
+            HashKey::Name => read.key("name"),
+            HashKey::Ch => read.key("ch"),
+            HashKey::Stored => unreachable!(),
+        }
+        .unwrap_or_else(|| panic!("read {} has no {:?}", read.name, hash_key));
+        parts[part_of(&key, num_parts)].push(read);
+    }
+    parts
+        .into_iter()
+        .enumerate()
+        .filter(|(_, batch)| !batch.is_empty())
+        .map(|(part, batch)| (part, batch.to_gas_record(key_kind)))
+        .collect()
+}
+
+fn hash_split(cli: &Cli, reader: &Arc<GasFileReader>, num_parts: usize) -> Vec<RollingShard> {
+    let mut shards = vec![];
+    let mut writers: Vec<(Arc<GasFileWriter>, Sender<GasRecord>)> = vec![];
+    for part in 0..num_parts {
+        let path = shard_path(&cli.out_prefix, part);
+        let (writer, sender) = GasFileWriter::new_writer(&path, NonZero::new(1).unwrap());
+        writer.set_codec(reader.codec());
+        for name in reader.user_sections() {
+            writer.add_section(name, reader.section(name).unwrap());
+        }
+        writer.start_write_worker();
+        writers.push((writer, sender));
+        shards.push(RollingShard {
+            path,
+            num_records: 0,
+            num_bytes: 0,
+        });
+    }
+
+    let pb = get_spin_pb(format!("splitting {}", cli.in_path), DEFAULT_INTERVAL);
+    let records = match cli.hash_key {
+        HashKey::Stored => reader.gas_records().stored(),
+        HashKey::Name | HashKey::Ch => {
+            check_reads_format(reader.section(READS_FORMAT_SECTION).as_deref());
+            reader.gas_records()
+        }
+    };
+    for record in records {
+        pb.inc(1);
+        for (part, record) in split_record(record, cli.hash_key, num_parts) {
+            shards[part].num_records += 1;
+            shards[part].num_bytes += record.data.len() as u64;
+            writers[part].1.send(record).unwrap();
+        }
+    }
+    for (writer, sender) in writers {
+        drop(sender);
+        writer.wait_for_write_done();
+    }
+    pb.finish();
+
+    write_manifest(&manifest_path(&cli.out_prefix), &shards);
+    shards
+}
+
+fn main() {
+    let cli = Cli::parse();
+    let (reader, _recv) = GasFileReader::new_reader(&cli.in_path, NonZero::new(1).unwrap());
+
+    let shards = if let Some(num_parts) = cli.hash_parts {
+        hash_split(&cli, &reader, num_parts.get())
+    } else {
+        let (writer, sender) = RollingGasFileWriter::new_writer(
+            &cli.out_prefix,
+            NonZero::new(1).unwrap(),
+            cli.max_payload_bytes.map(NonZero::get),
+            cli.max_records.map(NonZero::get),
+        );
+        writer.set_codec(reader.codec());
+        for name in reader.user_sections() {
+            writer.add_section(name, reader.section(name).unwrap());
+        }
+        writer.start_write_worker();
+        let pb = get_spin_pb(format!("splitting {}", cli.in_path), DEFAULT_INTERVAL);
+        // payloads are copied as they are stored
+        for record in reader.gas_records().stored() {
+            pb.inc(1);
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        let shards = writer.wait_for_write_done();
+        pb.finish();
+        shards
+    };
+
+    for shard in shards {
+        println!(
+            "{}\trecords:{}\tbytes:{}",
+            shard.path.display(),
+            shard.num_records,
+            shard.num_bytes
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, num::NonZero};
+
+    use clap::Parser;
+    use gas::{
+        io::{
+            rolling::{RollingShard, manifest_path, read_manifest},
+            v1::{GasFileReader, GasFileWriter, GasRecord, get_bincode_cfg},
+        },
+        reads::{BatchReads, READS_FORMAT_SECTION, ReadInfo, reads_format_section},
+    };
+    use tempfile::TempDir;
+
+    use super::{Cli, HashKey, hash_split, part_of, split_record, stored_key_kind};
+
+    /// reads r{i} of zmw i / 2
+    fn batch(range: std::ops::Range<u32>) -> BatchReads {
+        BatchReads(
+            range
+                .map(|i| ReadInfo {
+                    ch: Some(i / 2),
+                    ..ReadInfo::new_fa_record(format!("r{}", i), "ACGT".to_string())
+                })
+                .collect(),
+        )
+    }
+
+    fn decode(record: &GasRecord) -> BatchReads {
+        bincode::decode_from_slice(&record.data, get_bincode_cfg())
+            .unwrap()
+            .0
+    }
+
+    fn names(batch: &BatchReads) -> Vec<String> {
+        batch.iter().map(|read| read.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_stored_key_kind() {
+        let batch = batch(0..3);
+        let names = [b"r0".to_vec(), b"r1".to_vec(), b"r2".to_vec()];
+        assert_eq!(stored_key_kind(&batch, &names), Some("name"));
+        assert_eq!(
+            stored_key_kind(&batch, &[b"0".to_vec(), b"1".to_vec()]),
+            Some("ch")
+        );
+        assert_eq!(stored_key_kind(&batch, &names[..2]), None);
+        assert_eq!(stored_key_kind(&batch, &[]), None);
+    }
+
+    #[test]
+    fn test_split_record_stored() {
+        let record = GasRecord::new(vec![1, 2, 3]).with_key("k2").with_key("k1");
+        let parts = split_record(record, HashKey::Stored, 7);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(parts[0].0, part_of(b"k1", 7));
+        assert_eq!(parts[0].1.data, vec![1, 2, 3]);
+        assert_eq!(parts[0].1.keys, vec![b"k2".to_vec(), b"k1".to_vec()]);
+    }
+
+    #[test]
+    #[should_panic(expected = "needs keys")]
+    fn test_split_record_stored_without_keys() {
+        split_record(GasRecord::new(vec![1]), HashKey::Stored, 2);
+    }
+
+    #[test]
+    fn test_split_record_reads() {
+        let batch = batch(0..40);
+        let record = batch.to_gas_record(Some("name"));
+        let parts = split_record(record, HashKey::Name, 4);
+        assert!(parts.len() > 1);
+        let mut split_names = vec![];
+        for (part, record) in parts {
+            let reads = decode(&record);
+            for read in reads.iter() {
+                assert_eq!(part, part_of(read.name.as_bytes(), 4));
+            }
+            // the keys are rebuilt from the reads of the part, of the same kind as before
+            let mut keys = names(&reads)
+                .into_iter()
+                .map(String::into_bytes)
+                .collect::<Vec<_>>();
+            keys.sort();
+            assert_eq!(record.keys, keys);
+            split_names.extend(names(&reads));
+        }
+        split_names.sort();
+        let mut expected = names(&batch);
+        expected.sort();
+        assert_eq!(split_names, expected);
+
+        // reads of a zmw stay together, a batch without keys is split without keys
+        let parts = split_record(batch.to_gas_record(None), HashKey::Ch, 4);
+        for (part, record) in parts {
+            assert!(record.keys.is_empty());
+            for read in decode(&record).iter() {
+                assert_eq!(part, part_of(read.ch.unwrap().to_string().as_bytes(), 4));
+            }
+        }
+    }
+
+    fn write_reads(path: &std::path::Path, format: bool) {
+        let (writer, sender) = GasFileWriter::new_writer(path, NonZero::new(1).unwrap());
+        if format {
+            writer.add_section(READS_FORMAT_SECTION, reads_format_section());
+        }
+        writer.add_section("user.note", b"hello".to_vec());
+        writer.start_write_worker();
+        for i in 0..10 {
+            sender
+                .send(batch(i * 10..(i + 1) * 10).to_gas_record(Some("ch")))
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+    }
+
+    fn run(dir: &TempDir, hash_key: &str) -> Vec<RollingShard> {
+        let in_path = dir.path().join("in.gas");
+        let prefix = dir.path().join("out");
+        let cli = Cli::try_parse_from([
+            "gas-split",
+            in_path.to_str().unwrap(),
+            "-o",
+            prefix.to_str().unwrap(),
+            "--hash-parts",
+            "3",
+            "--hash-key",
+            hash_key,
+        ])
+        .unwrap();
+        let (reader, _recv) = GasFileReader::new_reader(&in_path, NonZero::new(1).unwrap());
+        hash_split(&cli, &reader, 3)
+    }
+
+    #[test]
+    fn test_hash_split() {
+        let dir = TempDir::new().unwrap();
+        write_reads(&dir.path().join("in.gas"), true);
+        let shards = run(&dir, "ch");
+        assert_eq!(shards.len(), 3);
+        let manifest = read_manifest(manifest_path(dir.path().join("out")));
+        assert_eq!(
+            manifest.iter().map(|shard| &shard.path).collect::<Vec<_>>(),
+            shards.iter().map(|shard| &shard.path).collect::<Vec<_>>()
+        );
+
+        // zmw -> the shard its reads are in
+        let mut zmw_shards = HashMap::new();
+        let mut num_reads = 0;
+        for (shard_idx, shard) in shards.iter().enumerate() {
+            let (reader, _recv) = GasFileReader::new_reader(&shard.path, NonZero::new(1).unwrap());
+            assert_eq!(reader.num_records(), shard.num_records);
+            assert_eq!(reader.section("user.note"), Some(b"hello".to_vec()));
+            for record in reader.gas_records() {
+                for read in decode(&record).iter() {
+                    num_reads += 1;
+                    let zmw_shard = *zmw_shards.entry(read.ch.unwrap()).or_insert(shard_idx);
+                    assert_eq!(zmw_shard, shard_idx);
+                }
+            }
+        }
+        assert_eq!(num_reads, 100);
+        assert_eq!(zmw_shards.len(), 50);
+    }
+
+    #[test]
+    #[should_panic(expected = "older version")]
+    fn test_hash_split_reads_format() {
+        let dir = TempDir::new().unwrap();
+        write_reads(&dir.path().join("in.gas"), false);
+        run(&dir, "name");
+    }
+}
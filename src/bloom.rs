@@ -0,0 +1,88 @@
+/// 64 bits FNV-1a followed by the splitmix64 finalizer.
+/// unlike std DefaultHasher it is stable across rust versions and platforms, so the result can be persisted
+pub fn stable_hash64(data: &[u8], seed: u64) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64 ^ seed;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// bloom filter over byte keys, k probes are derived from two hashes (double hashing)
+#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// sized for `num_items` keys with the false positive rate `fp_rate`
+    pub fn new(num_items: usize, fp_rate: f64) -> Self {
+        let num_items = num_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-num_items * fp_rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let num_hashes = (num_bits / num_items * ln2).round().clamp(1.0, 16.0);
+        Self {
+            bits: vec![0; (num_bits as usize).div_ceil(64)],
+            num_hashes: num_hashes as u32,
+        }
+    }
+
+    pub fn from_keys<'a, I>(keys: I, num_items: usize, fp_rate: f64) -> Self
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut bloom = Self::new(num_items, fp_rate);
+        keys.into_iter().for_each(|key| bloom.insert(key));
+        bloom
+    }
+
+    fn probes(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let num_bits = self.bits.len() as u64 * 64;
+        let h1 = stable_hash64(key, 0);
+        let h2 = stable_hash64(key, h1) | 1;
+        (0..self.num_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.probes(key).collect::<Vec<_>>() {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// false means the key is definitely not inserted
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.probes(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BloomFilter;
+
+    #[test]
+    fn test_bloom_filter() {
+        let keys = (0..10_000)
+            .map(|i| format!("m64011/{}/ccs", i))
+            .collect::<Vec<_>>();
+        let bloom = BloomFilter::from_keys(keys.iter().map(|k| k.as_bytes()), keys.len(), 0.01);
+        assert!(keys.iter().all(|k| bloom.may_contain(k.as_bytes())));
+
+        let false_positives = (10_000..20_000)
+            .filter(|i| bloom.may_contain(format!("m64011/{}/ccs", i).as_bytes()))
+            .count();
+        assert!(
+            false_positives < 200,
+            "false_positives: {}",
+            false_positives
+        );
+
+        let empty = BloomFilter::new(0, 0.01);
+        assert!(!empty.may_contain(b"m64011/0/ccs"));
+    }
+}
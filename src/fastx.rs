@@ -0,0 +1,253 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use rust_htslib::bgzf;
+
+use crate::reads::ReadInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastxFormat {
+    Fasta,
+    Fastq,
+}
+
+impl FastxFormat {
+    /// by the extension, a trailing .gz is ignored. e.g. reads.fq.gz is fastq
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let name = path.as_ref().file_name()?.to_str()?;
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        match name.rsplit_once('.')?.1 {
+            "fa" | "fasta" | "fna" => Some(Self::Fasta),
+            "fq" | "fastq" => Some(Self::Fastq),
+            _ => None,
+        }
+    }
+}
+
+/// reads a fasta or fastq file, the format is judged by the first record.
+/// multi-line fasta is supported, fastq records must be 4 lines. the name is the header up to the first whitespace
+pub struct FastxReader<R: BufRead> {
+    input: R,
+    line: String,
+    format: Option<FastxFormat>,
+    line_no: usize,
+}
+
+impl FastxReader<BufReader<bgzf::Reader>> {
+    /// plain and gzipped files are both accepted
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        let reader = bgzf::Reader::from_path(&path)
+            .unwrap_or_else(|err| panic!("open {:?} error. {}", path.as_ref(), err));
+        Self::new(BufReader::new(reader))
+    }
+}
+
+impl<R: BufRead> FastxReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            line: String::new(),
+            format: None,
+            line_no: 0,
+        }
+    }
+
+    /// the next line into self.line without the line break, false at the end of the file
+    fn next_line(&mut self) -> bool {
+        self.line.clear();
+        if self.input.read_line(&mut self.line).unwrap() == 0 {
+            return false;
+        }
+        self.line_no += 1;
+        let len = self.line.trim_end_matches(['\n', '\r']).len();
+        self.line.truncate(len);
+        true
+    }
+
+    fn name(&self) -> String {
+        self.line[1..]
+            .split_ascii_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
+impl<R: BufRead> Iterator for FastxReader<R> {
+    type Item = ReadInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // the header line is already in self.line for multi-line fasta
+        if !self.line.starts_with('>') {
+            while self.line.is_empty() {
+                if !self.next_line() {
+                    return None;
+                }
+            }
+        }
+        let format = *self.format.get_or_insert(match self.line.as_bytes()[0] {
+            b'>' => FastxFormat::Fasta,
+            b'@' => FastxFormat::Fastq,
+            _ => panic!(
+                "line {}: not a fasta/fastq header. {}",
+                self.line_no, self.line
+            ),
+        });
+        let name = self.name();
+
+        match format {
+            FastxFormat::Fasta => {
+                assert!(
+                    self.line.starts_with('>'),
+                    "line {}: invalid fasta header. {}",
+                    self.line_no,
+                    self.line
+                );
+                let mut seq = String::new();
+                // stops at the next header, or at the end of the file with an empty line
+                while self.next_line() && !self.line.starts_with('>') {
+                    seq.push_str(self.line.trim());
+                }
+                Some(ReadInfo::new_fa_record(name, seq))
+            }
+            FastxFormat::Fastq => {
+                assert!(
+                    self.line.starts_with('@'),
+                    "line {}: invalid fastq header. {}",
+                    self.line_no,
+                    self.line
+                );
+                assert!(self.next_line(), "fastq record {} is truncated", name);
+                let seq = self.line.clone();
+                assert!(
+                    self.next_line() && self.line.starts_with('+'),
+                    "line {}: expect the + line of fastq record {}",
+                    self.line_no,
+                    name
+                );
+                assert!(self.next_line(), "fastq record {} is truncated", name);
+                assert_eq!(
+                    self.line.len(),
+                    seq.len(),
+                    "line {}: the qual length of fastq record {} differs from the seq length",
+                    self.line_no,
+                    name
+                );
+                let qual = self
+                    .line
+                    .bytes()
+                    .map(|q| {
+                        assert!(
+                            q >= b'!',
+                            "line {}: invalid quality {:?} in fastq record {}",
+                            self.line_no,
+                            q as char,
+                            name
+                        );
+                        q - 33
+                    })
+                    .collect();
+                self.line.clear();
+                Some(ReadInfo::new_fq_record(name, seq, qual))
+            }
+        }
+    }
+}
+
+/// gzip compressed (bgzf) if the path ends with .gz
+pub fn create_fastx_writer<P: AsRef<Path>>(path: P) -> Box<dyn Write> {
+    let path = path.as_ref();
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(
+            bgzf::Writer::from_path(path)
+                .unwrap_or_else(|err| panic!("create {:?} error. {}", path, err)),
+        )
+    } else {
+        Box::new(BufWriter::new(fs::File::create(path).unwrap_or_else(
+            |err| panic!("create {:?} error. {}", path, err),
+        )))
+    }
+}
+
+/// append the fasta/fastq text of a read. a read without qualities gets `!` (phred 0) in fastq
+pub fn write_fastx_record(out: &mut Vec<u8>, read: &ReadInfo, format: FastxFormat) {
+    match format {
+        FastxFormat::Fasta => {
+            out.push(b'>');
+            out.extend_from_slice(read.name.as_bytes());
+            out.push(b'\n');
+            out.extend_from_slice(read.seq.as_bytes());
+            out.push(b'\n');
+        }
+        FastxFormat::Fastq => {
+            out.push(b'@');
+            out.extend_from_slice(read.name.as_bytes());
+            out.push(b'\n');
+            out.extend_from_slice(read.seq.as_bytes());
+            out.extend_from_slice(b"\n+\n");
+            match &read.qual {
+                // bam stores 0xff if the qualities are missing
+                Some(qual) if qual.first() != Some(&0xff) => {
+                    out.extend(qual.iter().map(|q| q.min(&93) + 33))
+                }
+                _ => out.extend(std::iter::repeat_n(b'!', read.seq.len())),
+            }
+            out.push(b'\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::{FastxFormat, FastxReader, write_fastx_record};
+
+    #[test]
+    fn test_fastx_round_trip() {
+        let fasta = ">r1 some description\nACGT\nAC\n\n>r2\nGG\n";
+        let reads = FastxReader::new(Cursor::new(fasta)).collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2);
+        assert_eq!(
+            (reads[0].name.as_str(), reads[0].seq.as_str()),
+            ("r1", "ACGTAC")
+        );
+        assert_eq!(
+            (reads[1].name.as_str(), reads[1].seq.as_str()),
+            ("r2", "GG")
+        );
+        assert_eq!(reads[0].qual, None);
+
+        let fastq = "@r1\nACGT\n+\n!+5I\n@r2 x\nG\n+r2\n#\n";
+        let reads = FastxReader::new(Cursor::new(fastq)).collect::<Vec<_>>();
+        assert_eq!(reads.len(), 2);
+        assert_eq!(reads[0].qual, Some(vec![0, 10, 20, 40]));
+        assert_eq!(reads[1].name, "r2");
+
+        let mut out = vec![];
+        for read in &reads {
+            write_fastx_record(&mut out, read, FastxFormat::Fastq);
+        }
+        assert_eq!(out, b"@r1\nACGT\n+\n!+5I\n@r2\nG\n+\n#\n");
+
+        assert_eq!(
+            FastxFormat::from_path("a/reads.fq.gz"),
+            Some(FastxFormat::Fastq)
+        );
+        assert_eq!(
+            FastxFormat::from_path("reads.fasta"),
+            Some(FastxFormat::Fasta)
+        );
+        assert_eq!(FastxFormat::from_path("reads.gas"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "line 4: invalid quality ' ' in fastq record r1")]
+    fn test_fastq_invalid_quality() {
+        let fastq = "@r1\nACGT\n+\n!+ I\n";
+        FastxReader::new(Cursor::new(fastq)).for_each(drop);
+    }
+}
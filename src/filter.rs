@@ -0,0 +1,127 @@
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    Eq,
+    Ne,
+}
+
+impl CmpOp {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CmpOp::Ge => ">=",
+            CmpOp::Gt => ">",
+            CmpOp::Le => "<=",
+            CmpOp::Lt => "<",
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+        }
+    }
+}
+
+/// `name op value` predicate on a numeric statistic, e.g. `rq>=0.99`, `np>3`, `len<=20000`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatFilter {
+    pub name: String,
+    pub op: CmpOp,
+    pub value: f64,
+}
+
+impl StatFilter {
+    pub fn matches(&self, v: f64) -> bool {
+        match self.op {
+            CmpOp::Ge => v >= self.value,
+            CmpOp::Gt => v > self.value,
+            CmpOp::Le => v <= self.value,
+            CmpOp::Lt => v < self.value,
+            CmpOp::Eq => v == self.value,
+            CmpOp::Ne => v != self.value,
+        }
+    }
+
+    /// whether a value within [min, max] may match. min > max means there is no value at all
+    pub fn may_match(&self, min: f64, max: f64) -> bool {
+        if min > max {
+            return false;
+        }
+        match self.op {
+            CmpOp::Ge => max >= self.value,
+            CmpOp::Gt => max > self.value,
+            CmpOp::Le => min <= self.value,
+            CmpOp::Lt => min < self.value,
+            CmpOp::Eq => min <= self.value && self.value <= max,
+            CmpOp::Ne => !(min == self.value && max == self.value),
+        }
+    }
+}
+
+impl FromStr for StatFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // two chars operators go first, so that `>=` is not taken as `>`
+        let ops = [
+            CmpOp::Ge,
+            CmpOp::Le,
+            CmpOp::Eq,
+            CmpOp::Ne,
+            CmpOp::Gt,
+            CmpOp::Lt,
+        ];
+        for op in ops {
+            if let Some((name, value)) = s.split_once(op.as_str()) {
+                let name = name.trim();
+                let value = value
+                    .trim()
+                    .parse::<f64>()
+                    .map_err(|err| format!("invalid filter: {}. {}", s, err))?;
+                if name.is_empty() {
+                    return Err(format!("invalid filter: {}. name is empty", s));
+                }
+                return Ok(Self {
+                    name: name.to_string(),
+                    op,
+                    value,
+                });
+            }
+        }
+        Err(format!(
+            "invalid filter: {}. expected `name op value`, e.g. rq>=0.99",
+            s
+        ))
+    }
+}
+
+impl Display for StatFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.name, self.op.as_str(), self.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CmpOp, StatFilter};
+
+    #[test]
+    fn test_stat_filter() {
+        let filter: StatFilter = "rq >= 0.99".parse().unwrap();
+        assert_eq!(filter.name, "rq");
+        assert_eq!(filter.op, CmpOp::Ge);
+        assert!(filter.matches(0.995) && !filter.matches(0.98));
+        assert!(filter.may_match(0.9, 0.99) && !filter.may_match(0.9, 0.98));
+        assert!(!filter.may_match(f64::INFINITY, f64::NEG_INFINITY));
+        assert_eq!(filter.to_string(), "rq>=0.99");
+
+        let filter: StatFilter = "len<1000".parse().unwrap();
+        assert_eq!(filter.op, CmpOp::Lt);
+        assert!(filter.may_match(10.0, 20000.0) && !filter.may_match(1000.0, 20000.0));
+
+        assert!("rq".parse::<StatFilter>().is_err());
+        assert!(">=0.99".parse::<StatFilter>().is_err());
+        assert!("rq>=high".parse::<StatFilter>().is_err());
+    }
+}
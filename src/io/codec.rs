@@ -0,0 +1,90 @@
+use std::borrow::Cow;
+
+/// name of the section that holds the RecordCodec. files without it store the records as they are
+pub const CODEC_SECTION: &str = "gas.codec";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd {
+        level: i32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub enum Checksum {
+    #[default]
+    None,
+    /// crc32 of the stored (compressed) bytes, appended as 4 little endian bytes
+    Crc32,
+}
+
+/// how record payloads are stored on disk. applied to every record of a file,
+/// the index positions and lengths refer to the stored bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, bincode::Encode, bincode::Decode)]
+pub struct RecordCodec {
+    pub compression: Compression,
+    pub checksum: Checksum,
+}
+
+impl RecordCodec {
+    pub fn is_plain(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn encode<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut stored = match self.compression {
+            Compression::None => Cow::Borrowed(data),
+            Compression::Zstd { level } => Cow::Owned(zstd::bulk::compress(data, level).unwrap()),
+        };
+        if self.checksum == Checksum::Crc32 {
+            let crc = crc32fast::hash(&stored);
+            stored.to_mut().extend_from_slice(&crc.to_le_bytes());
+        }
+        stored
+    }
+
+    /// panics if the checksum does not match
+    pub fn decode(&self, mut stored: Vec<u8>) -> Vec<u8> {
+        if self.checksum == Checksum::Crc32 {
+            assert!(stored.len() >= 4, "record is too short to hold a checksum");
+            let crc = stored.split_off(stored.len() - 4);
+            assert_eq!(
+                crc32fast::hash(&stored).to_le_bytes(),
+                crc.as_slice(),
+                "record checksum mismatch, the file is corrupted"
+            );
+        }
+        match self.compression {
+            Compression::None => stored,
+            Compression::Zstd { .. } => zstd::stream::decode_all(stored.as_slice()).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Checksum, Compression, RecordCodec};
+
+    #[test]
+    fn test_record_codec() {
+        let data = b"ACGT".repeat(1000);
+        let codec = RecordCodec {
+            compression: Compression::Zstd { level: 3 },
+            checksum: Checksum::Crc32,
+        };
+        let stored = codec.encode(&data).into_owned();
+        assert!(stored.len() < data.len());
+        assert_eq!(codec.decode(stored.clone()), data);
+
+        let mut corrupted = stored;
+        corrupted[0] ^= 1;
+        assert!(std::panic::catch_unwind(|| codec.decode(corrupted)).is_err());
+
+        assert_eq!(
+            RecordCodec::default().encode(&data).as_ref(),
+            data.as_slice()
+        );
+    }
+}
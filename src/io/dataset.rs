@@ -0,0 +1,199 @@
+use std::{
+    num::NonZero,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, OnceLock,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use super::{rolling::read_manifest, v1::GasFileReader};
+
+/// a corpus that is split across many gas files, seen as one sequence of records.
+/// the global index of a record is its index inside its file plus the record count of all previous files
+pub struct GasDataset {
+    threads: usize,
+    readers: Vec<Arc<GasFileReader>>,
+    record_offsets: OnceLock<Vec<u64>>, // index of the first record of each file, plus the total count
+
+    file_cursor: AtomicUsize, // the file the read workers are working on
+    read_sender: Mutex<Option<Sender<Vec<u8>>>>,
+}
+
+impl GasDataset {
+    /// the receiver yields the records of all files once start_read_worker is called.
+    /// records of a file are read by all workers together, files are visited in the given order
+    pub fn new_dataset<P>(paths: &[P], threads: NonZero<usize>) -> (Arc<Self>, Receiver<Vec<u8>>)
+    where
+        P: AsRef<Path>,
+    {
+        let readers = paths
+            .iter()
+            .map(|p| GasFileReader::new_reader(p, threads).0)
+            .collect();
+
+        let (sender, recv) = crossbeam::channel::bounded(1000);
+        (
+            Self {
+                threads: threads.get(),
+                readers,
+                record_offsets: OnceLock::new(),
+                file_cursor: AtomicUsize::new(0),
+                read_sender: Mutex::new(sender.into()),
+            }
+            .into(),
+            recv,
+        )
+    }
+
+    /// open all files matching the glob pattern, sorted by path
+    pub fn from_glob(pattern: &str, threads: NonZero<usize>) -> (Arc<Self>, Receiver<Vec<u8>>) {
+        let mut paths = glob::glob(pattern)
+            .unwrap_or_else(|err| panic!("invalid glob pattern: {}. {}", pattern, err))
+            .map(|p| p.unwrap())
+            .collect::<Vec<PathBuf>>();
+        assert!(!paths.is_empty(), "no gas file matches {}", pattern);
+        paths.sort();
+        Self::new_dataset(&paths, threads)
+    }
+
+    /// open all files listed in the manifest written by RollingGasFileWriter
+    pub fn from_manifest<P>(manifest: P, threads: NonZero<usize>) -> (Arc<Self>, Receiver<Vec<u8>>)
+    where
+        P: AsRef<Path>,
+    {
+        let paths = read_manifest(manifest)
+            .into_iter()
+            .map(|shard| shard.path)
+            .collect::<Vec<_>>();
+        Self::new_dataset(&paths, threads)
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.readers.len()
+    }
+
+    pub fn readers(&self) -> &[Arc<GasFileReader>] {
+        &self.readers
+    }
+
+    /// total number of records over all files. the first call reads every index block of every file
+    pub fn num_records(&self) -> u64 {
+        *self.record_offsets().last().unwrap()
+    }
+
+    fn record_offsets(&self) -> &Vec<u64> {
+        self.record_offsets.get_or_init(|| {
+            let mut offsets = vec![0];
+            for reader in &self.readers {
+                offsets.push(offsets.last().unwrap() + reader.num_records());
+            }
+            offsets
+        })
+    }
+
+    /// random access to the `n`-th record of the dataset
+    pub fn get(&self, n: u64) -> Option<Vec<u8>> {
+        let offsets = self.record_offsets();
+        if n >= *offsets.last().unwrap() {
+            return None;
+        }
+        let file_idx = offsets.partition_point(|&offset| offset <= n) - 1;
+        self.readers[file_idx].get(n - offsets[file_idx])
+    }
+
+    /// the first record with the key, files whose bloom filter rules the key out are skipped
+    pub fn get_by_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.readers
+            .iter()
+            .find_map(|reader| reader.get_by_key(key))
+    }
+
+    pub fn start_read_worker(self: &Arc<Self>) {
+        let sender = self.read_sender.lock().unwrap().take();
+        if let Some(sender) = sender {
+            for _ in 0..self.threads {
+                thread::spawn({
+                    let dataset = Arc::clone(self);
+                    let sender = sender.clone();
+                    move || {
+                        dataset.read_worker(sender);
+                    }
+                });
+            }
+        }
+    }
+
+    fn read_worker(self: Arc<Self>, sender: Sender<Vec<u8>>) {
+        let mut file_idx = self.file_cursor.load(Ordering::SeqCst);
+        while file_idx < self.readers.len() {
+            let reader = &self.readers[file_idx];
+            while let Some(data) = reader.read() {
+                sender.send(data).unwrap();
+            }
+            // the file is exhausted, move on. other workers may have done it already
+            let _ = self.file_cursor.compare_exchange(
+                file_idx,
+                file_idx + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            file_idx = self.file_cursor.load(Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZero;
+
+    use tempfile::NamedTempFile;
+
+    use crate::io::v1::{GasFileWriter, get_bincode_cfg};
+
+    use super::GasDataset;
+
+    fn write_gas_file(values: std::ops::Range<u32>) -> NamedTempFile {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(1).unwrap());
+        writer.start_write_worker();
+        for i in values {
+            sender
+                .send(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap().into())
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+        named_file
+    }
+
+    #[test]
+    fn test_gas_dataset() {
+        let files = [
+            write_gas_file(0..2_500),
+            write_gas_file(2_500..2_501),
+            write_gas_file(2_501..4_000),
+        ];
+        let decode =
+            |v: Vec<u8>| -> u32 { bincode::decode_from_slice(&v, get_bincode_cfg()).unwrap().0 };
+
+        let (dataset, recv) = GasDataset::new_dataset(
+            &files.iter().map(|f| f.path()).collect::<Vec<_>>(),
+            NonZero::new(3).unwrap(),
+        );
+        assert_eq!(dataset.num_records(), 4_000);
+        for n in [0, 999, 1_000, 2_499, 2_500, 2_501, 3_999] {
+            assert_eq!(dataset.get(n).map(decode), Some(n as u32));
+        }
+        assert_eq!(dataset.get(4_000), None);
+
+        dataset.start_read_worker();
+        let mut results = recv.into_iter().map(decode).collect::<Vec<_>>();
+        results.sort();
+        assert_eq!(results, (0..4_000).collect::<Vec<_>>());
+    }
+}
@@ -1 +1,6 @@
-pub mod v1;
\ No newline at end of file
+pub mod codec;
+pub mod dataset;
+pub mod rolling;
+pub mod storage;
+pub mod stream;
+pub mod v1;
@@ -0,0 +1,388 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, BufWriter, Write},
+    num::NonZero,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use super::{
+    codec::RecordCodec,
+    v1::{GasFileWriter, GasRecord},
+};
+
+/// one of the files written by RollingGasFileWriter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollingShard {
+    pub path: PathBuf,
+    pub num_records: u64,
+    pub num_bytes: u64, // payload bytes as sent, see RollingGasFileWriter
+}
+
+/// writes `{prefix}.00000.gas`, `{prefix}.00001.gas`, ... and starts a new file before the
+/// current one exceeds `max_payload_bytes` payload bytes or `max_records` records, or its index would not fit.
+/// payload bytes are the GasRecord::data lengths as sent: the stored bytes of stored records, otherwise
+/// the bytes before the codec. the 2M header, the index and the sections are not counted, so they are
+/// no cap on the file size. a single record larger than `max_payload_bytes` still gets its own file.
+/// once all files are done, `{prefix}.manifest.tsv` lists every file with its record and byte count
+pub struct RollingGasFileWriter {
+    prefix: PathBuf,
+    threads: NonZero<usize>,
+    max_payload_bytes: Option<u64>,
+    max_records: Option<u64>,
+    sections: Mutex<Vec<(String, Vec<u8>)>>,
+    codec: Mutex<RecordCodec>,
+
+    writer_recv: Receiver<GasRecord>,
+    handler: Mutex<Option<thread::JoinHandle<Vec<RollingShard>>>>,
+}
+
+impl RollingGasFileWriter {
+    /// threads is the number of write threads of each GasFileWriter.
+    /// sender is used for to send data to be written. keys are kept in the file the record goes to
+    pub fn new_writer<P>(
+        prefix: P,
+        threads: NonZero<usize>,
+        max_payload_bytes: Option<u64>,
+        max_records: Option<u64>,
+    ) -> (Arc<Self>, Sender<GasRecord>)
+    where
+        P: AsRef<Path>,
+    {
+        let (sender, recv) = crossbeam::channel::bounded::<GasRecord>(1000);
+        (
+            Self {
+                prefix: prefix.as_ref().to_owned(),
+                threads,
+                max_payload_bytes,
+                max_records,
+                sections: Mutex::new(vec![]),
+                codec: Mutex::new(RecordCodec::default()),
+                writer_recv: recv,
+                handler: Mutex::new(None),
+            }
+            .into(),
+            sender,
+        )
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        manifest_path(&self.prefix)
+    }
+
+    /// store a named blob in every file, see GasFileWriter::add_section. must be called before start_write_worker
+    pub fn add_section(&self, name: &str, data: Vec<u8>) {
+        let mut sections = self.sections.lock().unwrap();
+        sections.retain(|(section_name, _)| section_name != name);
+        sections.push((name.to_string(), data));
+    }
+
+    /// see GasFileWriter::set_codec. must be called before start_write_worker
+    pub fn set_codec(&self, codec: RecordCodec) {
+        assert!(
+            self.handler.lock().unwrap().is_none(),
+            "set_codec must be called before start_write_worker"
+        );
+        *self.codec.lock().unwrap() = codec;
+    }
+
+    pub fn start_write_worker(self: &Arc<Self>) {
+        let mut handler = self.handler.lock().unwrap();
+        if handler.is_some() {
+            return;
+        }
+        *handler = Some(thread::spawn({
+            let self_clone = Arc::clone(self);
+            move || self_clone.dispatch_worker()
+        }));
+    }
+
+    fn dispatch_worker(&self) -> Vec<RollingShard> {
+        let mut shards: Vec<RollingShard> = vec![];
+        let mut cur_writer: Option<(Arc<GasFileWriter>, Sender<GasRecord>)> = None;
+
+        for record in self.writer_recv.clone() {
+            let need_roll = match (shards.last(), &cur_writer) {
+                (Some(shard), Some((writer, _))) => {
+                    self.limit_reached(shard, record.data.len() as u64)
+                        || (shard.num_records > 0 && !writer.has_room_for(shard.num_records + 1))
+                }
+                _ => true,
+            };
+            if need_roll {
+                if let Some((writer, sender)) = cur_writer.take() {
+                    drop(sender);
+                    writer.wait_for_write_done();
+                }
+                let path = shard_path(&self.prefix, shards.len());
+                let (writer, sender) = GasFileWriter::new_writer(&path, self.threads);
+                writer.set_codec(*self.codec.lock().unwrap());
+                for (name, data) in self.sections.lock().unwrap().iter() {
+                    writer.add_section(name, data.clone());
+                }
+                writer.start_write_worker();
+                cur_writer = Some((writer, sender));
+                shards.push(RollingShard {
+                    path,
+                    num_records: 0,
+                    num_bytes: 0,
+                });
+            }
+
+            let shard = shards.last_mut().unwrap();
+            shard.num_records += 1;
+            shard.num_bytes += record.data.len() as u64;
+            cur_writer.as_ref().unwrap().1.send(record).unwrap();
+        }
+
+        if let Some((writer, sender)) = cur_writer.take() {
+            drop(sender);
+            writer.wait_for_write_done();
+        }
+        write_manifest(&self.manifest_path(), &shards);
+        shards
+    }
+
+    fn limit_reached(&self, shard: &RollingShard, data_len: u64) -> bool {
+        if self
+            .max_records
+            .is_some_and(|max_records| shard.num_records >= max_records)
+        {
+            return true;
+        }
+        self.max_payload_bytes
+            .is_some_and(|max_payload_bytes| shard.num_bytes + data_len > max_payload_bytes)
+    }
+
+    /// wait until all files and the manifest are written
+    pub fn wait_for_write_done(self: Arc<Self>) -> Vec<RollingShard> {
+        let handler = self
+            .handler
+            .lock()
+            .unwrap()
+            .take()
+            .expect("start_write_worker is not called");
+        handler.join().unwrap()
+    }
+}
+
+fn path_with_suffix(prefix: &Path, suffix: &str) -> PathBuf {
+    let mut path = prefix.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// `{prefix}.{idx:05}.gas`, the `idx`-th file of a sharded output
+pub fn shard_path<P: AsRef<Path>>(prefix: P, idx: usize) -> PathBuf {
+    path_with_suffix(prefix.as_ref(), &format!(".{:05}.gas", idx))
+}
+
+/// `{prefix}.manifest.tsv`
+pub fn manifest_path<P: AsRef<Path>>(prefix: P) -> PathBuf {
+    path_with_suffix(prefix.as_ref(), ".manifest.tsv")
+}
+
+/// path\tnum_records\tnum_bytes. paths are relative to the directory of the manifest
+pub fn write_manifest(manifest: &Path, shards: &[RollingShard]) {
+    let mut writer = BufWriter::new(fs::File::create(manifest).unwrap());
+    writeln!(writer, "path\tnum_records\tnum_bytes").unwrap();
+    for shard in shards {
+        writeln!(
+            writer,
+            "{}\t{}\t{}",
+            shard.path.file_name().unwrap().to_str().unwrap(),
+            shard.num_records,
+            shard.num_bytes
+        )
+        .unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+/// read the manifest written by RollingGasFileWriter, the returned paths are resolved against the directory of the manifest
+pub fn read_manifest<P>(manifest: P) -> Vec<RollingShard>
+where
+    P: AsRef<Path>,
+{
+    let manifest = manifest.as_ref();
+    let dir = manifest.parent().unwrap_or(Path::new(""));
+    let reader = BufReader::new(
+        fs::File::open(manifest)
+            .unwrap_or_else(|err| panic!("open manifest {:?} error. {}", manifest, err)),
+    );
+    reader
+        .lines()
+        .skip(1)
+        .map(|line| line.unwrap())
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let items = line.split('\t').collect::<Vec<_>>();
+            assert_eq!(items.len(), 3, "invalid manifest line: {}", line);
+            RollingShard {
+                path: dir.join(items[0]),
+                num_records: items[1].parse().unwrap(),
+                num_bytes: items[2].parse().unwrap(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZero;
+
+    use tempfile::TempDir;
+
+    use crate::io::{
+        codec::{Checksum, Compression, RecordCodec},
+        dataset::GasDataset,
+        v1::{GasFileReader, GasFileWriter, GasRecord, META_CAPACITY, get_bincode_cfg},
+    };
+
+    use super::{RollingGasFileWriter, read_manifest};
+
+    #[test]
+    fn test_rolling_writer() {
+        let dir = TempDir::new().unwrap();
+        let (writer, sender) = RollingGasFileWriter::new_writer(
+            dir.path().join("out"),
+            NonZero::new(2).unwrap(),
+            None,
+            Some(1_000),
+        );
+        writer.add_section("user.meta", b"v1".to_vec());
+        writer.start_write_worker();
+        for i in 0_u32..2_500 {
+            sender
+                .send(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap().into())
+                .unwrap();
+        }
+        drop(sender);
+        let manifest = writer.manifest_path();
+        let shards = writer.wait_for_write_done();
+
+        assert_eq!(
+            shards.iter().map(|s| s.num_records).collect::<Vec<_>>(),
+            vec![1_000, 1_000, 500]
+        );
+        assert_eq!(shards[1].path, dir.path().join("out.00001.gas"));
+        assert_eq!(read_manifest(&manifest), shards);
+
+        let (dataset, _recv) = GasDataset::from_manifest(&manifest, NonZero::new(1).unwrap());
+        assert_eq!(dataset.num_records(), 2_500);
+        assert!(
+            dataset
+                .readers()
+                .iter()
+                .all(|reader| reader.section("user.meta") == Some(b"v1".to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_rolling_writer_stored_records() {
+        let dir = TempDir::new().unwrap();
+        let codec = RecordCodec {
+            compression: Compression::Zstd { level: 3 },
+            checksum: Checksum::Crc32,
+        };
+        let (writer, sender) =
+            GasFileWriter::new_writer(dir.path().join("in.gas"), NonZero::new(1).unwrap());
+        writer.set_codec(codec);
+        writer.add_section("user.meta", b"v1".to_vec());
+        writer.start_write_worker();
+        for i in 0_u32..2_500 {
+            let data = format!("ACGT{}", i).repeat(20).into_bytes();
+            sender
+                .send(GasRecord::new(data).with_key(i.to_string()))
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+        let (reader, _recv) =
+            GasFileReader::new_reader(dir.path().join("in.gas"), NonZero::new(1).unwrap());
+
+        // stored records are written as they are, with their keys and the sections, in every shard
+        let (writer, sender) = RollingGasFileWriter::new_writer(
+            dir.path().join("out"),
+            NonZero::new(1).unwrap(),
+            None,
+            Some(1_000),
+        );
+        writer.set_codec(reader.codec());
+        for name in reader.user_sections() {
+            writer.add_section(name, reader.section(name).unwrap());
+        }
+        writer.start_write_worker();
+        for record in reader.gas_records().stored() {
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        let shards = writer.wait_for_write_done();
+
+        assert_eq!(shards.len(), 3);
+        let mut idx = 0;
+        for shard in shards {
+            let (part, _recv) = GasFileReader::new_reader(&shard.path, NonZero::new(1).unwrap());
+            assert_eq!(part.codec(), codec);
+            assert_eq!(part.section("user.meta"), Some(b"v1".to_vec()));
+            for n in 0..part.num_records() {
+                assert_eq!(part.get_stored(n), reader.get_stored(idx));
+                idx += 1;
+            }
+            assert_eq!(
+                part.get_by_key((idx - 1).to_string().as_bytes()),
+                reader.get(idx - 1)
+            );
+        }
+        assert_eq!(idx, 2_500);
+    }
+
+    #[test]
+    fn test_rolling_writer_max_payload_bytes() {
+        let dir = TempDir::new().unwrap();
+        let (writer, sender) = RollingGasFileWriter::new_writer(
+            dir.path().join("out"),
+            NonZero::new(1).unwrap(),
+            Some(250),
+            None,
+        );
+        writer.start_write_worker();
+        for _ in 0..10 {
+            sender.send(vec![0_u8; 100].into()).unwrap();
+        }
+        drop(sender);
+        let shards = writer.wait_for_write_done();
+        assert_eq!(shards.len(), 5);
+        assert!(shards.iter().all(|s| s.num_bytes == 200));
+    }
+
+    #[test]
+    fn test_rolling_writer_index_capacity() {
+        let dir = TempDir::new().unwrap();
+        let (writer, sender) = RollingGasFileWriter::new_writer(
+            dir.path().join("out"),
+            NonZero::new(2).unwrap(),
+            None,
+            None,
+        );
+        // the section table takes most of the header, so a file indexes a few tens of blocks
+        let name = "x".repeat(META_CAPACITY as usize - 1_000);
+        writer.add_section(&name, vec![]);
+        writer.start_write_worker();
+        for i in 0_u32..100_000 {
+            sender
+                .send(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap().into())
+                .unwrap();
+        }
+        drop(sender);
+        let manifest = writer.manifest_path();
+        let shards = writer.wait_for_write_done();
+
+        assert!(shards.len() > 1, "{:?}", shards);
+        let (dataset, _recv) = GasDataset::from_manifest(&manifest, NonZero::new(1).unwrap());
+        assert_eq!(dataset.num_records(), 100_000);
+    }
+}
@@ -0,0 +1,65 @@
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    sync::Mutex,
+};
+
+/// positional reads and writes a gas file is stored on. all methods take `&self`,
+/// so one storage is shared by every read/write worker
+pub trait GasStorage: Send + Sync {
+    /// fails with UnexpectedEof if there are not enough bytes after `pos`
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()>;
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()>;
+
+    fn flush(&self) -> io::Result<()>;
+}
+
+impl GasStorage for fs::File {
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        FileExt::read_exact_at(self, buf, pos)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        FileExt::write_all_at(self, buf, pos)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+}
+
+/// any seekable backend, e.g. `Cursor<Vec<u8>>`. accesses are serialized by a mutex
+pub struct SeekStorage<T>(Mutex<T>);
+
+impl<T> SeekStorage<T> {
+    pub fn new(inner: T) -> Self {
+        Self(Mutex::new(inner))
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().unwrap()
+    }
+}
+
+impl<T> GasStorage for SeekStorage<T>
+where
+    T: Read + Write + Seek + Send,
+{
+    fn read_exact_at(&self, buf: &mut [u8], pos: u64) -> io::Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.seek(SeekFrom::Start(pos))?;
+        inner.read_exact(buf)
+    }
+
+    fn write_all_at(&self, buf: &[u8], pos: u64) -> io::Result<()> {
+        let mut inner = self.0.lock().unwrap();
+        inner.seek(SeekFrom::Start(pos))?;
+        inner.write_all(buf)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
@@ -0,0 +1,360 @@
+//! gas stream, a variant of the gas file that is written strictly sequentially, so it can go to stdout or a pipe.
+//!
+//! "GASS" | u32 version | u32 header len | bincode RecordCodec
+//! frames: u8 kind | u32 len | payload
+//!     record       the stored record bytes
+//!     index block  bincode Vec<u64>, stream offsets of the record frames since the previous index block
+//!     section      bincode (name, data). before the first record or after the last index block
+//!     end          bincode StreamIndex, stream offsets of all index block and section frames
+//! trailer: u64 stream offset of the end frame | "GASE"
+
+use std::{
+    borrow::Cow,
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use crossbeam::channel::{Receiver, Sender};
+
+use super::{
+    codec::RecordCodec,
+    v1::{GasRecord, get_bincode_cfg},
+};
+
+pub const GAS_STREAM_MAGIC: &[u8; 4] = b"GASS";
+pub const GAS_STREAM_END_MAGIC: &[u8; 4] = b"GASE";
+pub const GAS_STREAM_VERSION: u32 = 1;
+
+/// an index block is written after every RECORDS_PER_INDEX_BLOCK records, like GasFileWriter
+const RECORDS_PER_INDEX_BLOCK: usize = 1000;
+
+const FRAME_RECORD: u8 = 0;
+const FRAME_INDEX_BLOCK: u8 = 1;
+const FRAME_SECTION: u8 = 2;
+const FRAME_END: u8 = 3;
+
+/// the top level index, (offset, len) of each index block frame and (name, offset, len) of each section frame
+#[derive(Debug, Clone, Default, bincode::Encode, bincode::Decode)]
+pub struct StreamIndex {
+    pub blocks: Vec<(u64, u64)>,
+    pub sections: Vec<(String, u64, u64)>,
+}
+
+/// writes a gas stream from a single thread. keys and statistics of the records are not kept,
+/// a stream is read sequentially so there is nothing to look up
+pub struct GasStreamWriter {
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+    codec: Mutex<RecordCodec>,
+    sections: Mutex<Vec<(String, Vec<u8>)>>,
+
+    writer_recv: Receiver<GasRecord>,
+    handler: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+impl GasStreamWriter {
+    /// sender is used for to send data to be written, records are written in the order they are received
+    pub fn new_writer<W>(out: W) -> (Arc<Self>, Sender<GasRecord>)
+    where
+        W: Write + Send + 'static,
+    {
+        let (sender, recv) = crossbeam::channel::bounded::<GasRecord>(1000);
+        (
+            Self {
+                out: Mutex::new(Some(Box::new(BufWriter::new(out)))),
+                codec: Mutex::new(RecordCodec::default()),
+                sections: Mutex::new(vec![]),
+                writer_recv: recv,
+                handler: Mutex::new(None),
+            }
+            .into(),
+            sender,
+        )
+    }
+
+    /// see GasFileWriter::set_codec. must be called before start_write_worker
+    pub fn set_codec(&self, codec: RecordCodec) {
+        assert!(
+            self.handler.lock().unwrap().is_none(),
+            "set_codec must be called before start_write_worker"
+        );
+        *self.codec.lock().unwrap() = codec;
+    }
+
+    /// see GasFileWriter::add_section. sections added before start_write_worker are written
+    /// ahead of the records, so a reader gets them without consuming the stream. the others go to the end
+    pub fn add_section(&self, name: &str, data: Vec<u8>) {
+        let mut sections = self.sections.lock().unwrap();
+        sections.retain(|(section_name, _)| section_name != name);
+        sections.push((name.to_string(), data));
+    }
+
+    pub fn start_write_worker(self: &Arc<Self>) {
+        let mut handler = self.handler.lock().unwrap();
+        if handler.is_some() {
+            return;
+        }
+        let leading_sections = std::mem::take(&mut *self.sections.lock().unwrap());
+        *handler = Some(thread::spawn({
+            let self_clone = Arc::clone(self);
+            move || self_clone.write_worker(leading_sections)
+        }));
+    }
+
+    fn write_worker(&self, leading_sections: Vec<(String, Vec<u8>)>) {
+        let mut out = StreamOut {
+            out: self.out.lock().unwrap().take().unwrap(),
+            pos: 0,
+        };
+        let codec = *self.codec.lock().unwrap();
+        let cfg = get_bincode_cfg();
+
+        let header = bincode::encode_to_vec(codec, cfg).unwrap();
+        out.write(GAS_STREAM_MAGIC);
+        out.write(&GAS_STREAM_VERSION.to_le_bytes());
+        out.write(&(header.len() as u32).to_le_bytes());
+        out.write(&header);
+
+        let mut index = StreamIndex::default();
+        for (name, data) in leading_sections {
+            index.sections.push(out.section(name, data));
+        }
+        let mut block = Vec::with_capacity(RECORDS_PER_INDEX_BLOCK);
+        for record in self.writer_recv.clone() {
+            let data = if record.stored {
+                Cow::Borrowed(record.data.as_slice())
+            } else {
+                codec.encode(&record.data)
+            };
+            block.push(out.frame(FRAME_RECORD, &data).0);
+            if block.len() >= RECORDS_PER_INDEX_BLOCK {
+                let serial = bincode::encode_to_vec(std::mem::take(&mut block), cfg).unwrap();
+                index.blocks.push(out.frame(FRAME_INDEX_BLOCK, &serial));
+            }
+        }
+        if !block.is_empty() {
+            let serial = bincode::encode_to_vec(block, cfg).unwrap();
+            index.blocks.push(out.frame(FRAME_INDEX_BLOCK, &serial));
+        }
+
+        for (name, data) in std::mem::take(&mut *self.sections.lock().unwrap()) {
+            index.sections.push(out.section(name, data));
+        }
+
+        let serial = bincode::encode_to_vec(index, cfg).unwrap();
+        let (end_pos, _) = out.frame(FRAME_END, &serial);
+        out.write(&end_pos.to_le_bytes());
+        out.write(GAS_STREAM_END_MAGIC);
+        out.out.flush().unwrap();
+    }
+
+    /// wait until the trailer is written and flushed
+    pub fn wait_for_write_done(self: Arc<Self>) {
+        let handler = self
+            .handler
+            .lock()
+            .unwrap()
+            .take()
+            .expect("start_write_worker is not called");
+        handler.join().unwrap();
+    }
+}
+
+/// output that keeps track of the stream offset
+struct StreamOut {
+    out: Box<dyn Write + Send>,
+    pos: u64,
+}
+
+impl StreamOut {
+    fn write(&mut self, data: &[u8]) {
+        self.out.write_all(data).unwrap();
+        self.pos += data.len() as u64;
+    }
+
+    /// returns the offset and the total length of the frame
+    fn frame(&mut self, kind: u8, payload: &[u8]) -> (u64, u64) {
+        assert!(
+            payload.len() <= u32::MAX as usize,
+            "a frame of the gas stream holds at most 4GB, found {} bytes",
+            payload.len()
+        );
+        let pos = self.pos;
+        self.write(&[kind]);
+        self.write(&(payload.len() as u32).to_le_bytes());
+        self.write(payload);
+        (pos, self.pos - pos)
+    }
+
+    fn section(&mut self, name: String, data: Vec<u8>) -> (String, u64, u64) {
+        let serial = bincode::encode_to_vec((&name, data), get_bincode_cfg()).unwrap();
+        let (pos, len) = self.frame(FRAME_SECTION, &serial);
+        (name, pos, len)
+    }
+}
+
+/// reads a gas stream sequentially, e.g. from stdin. iterating yields the decoded records.
+/// sections ahead of the records are available after new, the others once all records are consumed
+pub struct GasStreamReader<R: Read> {
+    input: BufReader<R>,
+    codec: RecordCodec,
+    sections: Vec<(String, Vec<u8>)>,
+    finished: bool,
+}
+
+impl<R: Read> GasStreamReader<R> {
+    pub fn new(input: R) -> Self {
+        let mut input = BufReader::new(input);
+        let mut magic = [0_u8; 4];
+        input.read_exact(&mut magic).unwrap();
+        assert_eq!(&magic, GAS_STREAM_MAGIC, "not a gas stream");
+        let version = read_u32(&mut input);
+        assert_eq!(
+            version, GAS_STREAM_VERSION,
+            "Unsupported gas stream version. expected {}, found {}",
+            GAS_STREAM_VERSION, version
+        );
+        let mut header = vec![0_u8; read_u32(&mut input) as usize];
+        input.read_exact(&mut header).unwrap();
+        let (codec, _nbytes): (RecordCodec, usize) =
+            bincode::decode_from_slice(&header, get_bincode_cfg()).unwrap();
+        let mut reader = Self {
+            input,
+            codec,
+            sections: vec![],
+            finished: false,
+        };
+        while reader.input.fill_buf().unwrap().first() == Some(&FRAME_SECTION) {
+            let (_kind, payload) = reader.read_frame();
+            reader.push_section(&payload);
+        }
+        reader
+    }
+
+    pub fn codec(&self) -> RecordCodec {
+        self.codec
+    }
+
+    pub fn section_names(&self) -> Vec<&str> {
+        self.sections
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// None until all records are consumed, unless the section is ahead of the records
+    pub fn section(&self, name: &str) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|(section_name, _)| section_name == name)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// send every record, e.g. to the decode workers of bam-gas-cvt
+    pub fn read_worker(mut self, sender: Sender<Vec<u8>>) -> Self {
+        for data in self.by_ref() {
+            sender.send(data).unwrap();
+        }
+        self
+    }
+
+    fn read_frame(&mut self) -> (u8, Vec<u8>) {
+        let mut kind = [0_u8; 1];
+        match self.input.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                panic!("the gas stream is truncated, the end frame is missing")
+            }
+            Err(err) => panic!("read gas stream error. {}", err),
+        }
+        let mut payload = vec![0_u8; read_u32(&mut self.input) as usize];
+        self.input.read_exact(&mut payload).unwrap();
+        (kind[0], payload)
+    }
+
+    fn push_section(&mut self, payload: &[u8]) {
+        let (section, _nbytes): ((String, Vec<u8>), usize) =
+            bincode::decode_from_slice(payload, get_bincode_cfg()).unwrap();
+        self.sections.push(section);
+    }
+}
+
+impl<R: Read> Iterator for GasStreamReader<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.finished {
+            let (kind, payload) = self.read_frame();
+            match kind {
+                FRAME_RECORD => return Some(self.codec.decode(payload)),
+                FRAME_INDEX_BLOCK => {}
+                FRAME_SECTION => self.push_section(&payload),
+                FRAME_END => {
+                    let mut trailer = [0_u8; 12];
+                    self.input.read_exact(&mut trailer).unwrap();
+                    assert_eq!(
+                        &trailer[8..],
+                        GAS_STREAM_END_MAGIC,
+                        "invalid gas stream trailer"
+                    );
+                    self.finished = true;
+                }
+                kind => panic!("invalid gas stream frame kind: {}", kind),
+            }
+        }
+        None
+    }
+}
+
+fn read_u32<R: Read>(input: &mut R) -> u32 {
+    let mut bytes = [0_u8; 4];
+    input.read_exact(&mut bytes).unwrap();
+    u32::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use tempfile::NamedTempFile;
+
+    use super::{GasStreamReader, GasStreamWriter};
+    use crate::io::{
+        codec::{Checksum, Compression, RecordCodec},
+        v1::GasRecord,
+    };
+
+    #[test]
+    fn test_gas_stream() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) = GasStreamWriter::new_writer(named_file.reopen().unwrap());
+        writer.set_codec(RecordCodec {
+            compression: Compression::Zstd { level: 1 },
+            checksum: Checksum::Crc32,
+        });
+        writer.add_section("user.head", b"first".to_vec());
+        writer.start_write_worker();
+        writer.add_section("user.note", b"hello".to_vec());
+        for i in 0_u32..2_500 {
+            sender
+                .send(GasRecord::new(i.to_le_bytes().to_vec()).with_key(i.to_string()))
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let mut reader = GasStreamReader::new(fs::File::open(named_file.path()).unwrap());
+        assert_eq!(reader.section("user.head"), Some(b"first".as_slice()));
+        assert_eq!(reader.section("user.note"), None);
+        let records = reader.by_ref().collect::<Vec<_>>();
+        assert_eq!(records.len(), 2_500);
+        assert!(
+            records
+                .iter()
+                .enumerate()
+                .all(|(i, data)| data == &(i as u32).to_le_bytes())
+        );
+        assert_eq!(reader.section("user.note"), Some(b"hello".as_slice()));
+        assert_eq!(reader.next(), None);
+    }
+}
@@ -1,15 +1,22 @@
 use std::{
+    borrow::Cow,
     fs,
-    io::{Read, Seek, Write},
     num::NonZero,
     ops::{Deref, DerefMut},
-    path::{self, Path},
-    sync::{Arc, Barrier, Mutex, MutexGuard, atomic::AtomicBool},
+    path::{Path, PathBuf},
+    sync::{Arc, Barrier, Mutex, MutexGuard, OnceLock, atomic::AtomicBool},
     thread, usize,
 };
 
+use super::{
+    codec::{CODEC_SECTION, RecordCodec},
+    storage::GasStorage,
+};
+use crate::{bloom::BloomFilter, filter::StatFilter};
 use bincode::config::Configuration;
 use crossbeam::channel::{Receiver, Sender};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
 pub fn get_bincode_cfg() -> Configuration {
     bincode::config::standard()
@@ -48,10 +55,214 @@ impl DerefMut for WritePositionsMeta {
     }
 }
 
+impl WritePositionsMeta {
+    /// byte range [start, end) covered by each block: its records followed by its own index.
+    /// blocks are laid out back to back starting at DATA_OFFSET
+    fn block_spans(&self) -> Vec<(u64, u64)> {
+        let mut start = DATA_OFFSET;
+        self.iter()
+            .map(|&(pos, len)| {
+                let span = (start, pos + len);
+                start = pos + len;
+                span
+            })
+            .collect()
+    }
+}
+
+/// named blobs stored behind the records, (name, position, length).
+/// the table itself is stored in the header right after the first level index as u32 length + bincode
+#[derive(Debug, Clone, Default, bincode::Encode, bincode::Decode)]
+struct GasSections(Vec<(String, u64, u64)>);
+impl Deref for GasSections {
+    type Target = Vec<(String, u64, u64)>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for GasSections {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// sections named with it are maintained by the writer itself, e.g. gas.keys
+pub const RESERVED_SECTION_PREFIX: &str = "gas.";
+
+/// name of the section that holds the KeyIndex
+pub const KEY_INDEX_SECTION: &str = "gas.keys";
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, bincode::Encode, bincode::Decode)]
+pub struct KeyEntry {
+    pub key: Vec<u8>,
+    pub record_idx: u64,
+    pub position: u64,
+    pub len: u64,
+}
+
+/// key -> record table sorted by key, a record may have several keys and a key may map to several records
+#[derive(Debug, Clone, Default, bincode::Encode, bincode::Decode)]
+pub struct KeyIndex(Vec<KeyEntry>);
+impl Deref for KeyIndex {
+    type Target = Vec<KeyEntry>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl KeyIndex {
+    /// all entries of the key, O(log n)
+    pub fn find(&self, key: &[u8]) -> &[KeyEntry] {
+        let start = self.partition_point(|entry| entry.key.as_slice() < key);
+        let end = start + self[start..].partition_point(|entry| entry.key.as_slice() == key);
+        &self[start..end]
+    }
+}
+
+/// name of the section that holds the KeyBlooms
+pub const KEY_BLOOM_SECTION: &str = "gas.bloom";
+const KEY_BLOOM_FP_RATE: f64 = 0.01;
+
+/// bloom filters over the record keys of the whole file and of each index block
+#[derive(Debug, Clone, bincode::Encode, bincode::Decode)]
+pub struct KeyBlooms {
+    pub file: BloomFilter,
+    pub blocks: Vec<BloomFilter>,
+}
+
+impl KeyBlooms {
+    /// keys must be in record order
+    fn build(keys: &[KeyEntry], block_num_records: &[u64]) -> Self {
+        let mut blocks = vec![];
+        let mut first_record = 0;
+        let mut cursor = 0;
+        for &num_records in block_num_records {
+            let end = cursor
+                + keys[cursor..]
+                    .partition_point(|entry| entry.record_idx < first_record + num_records);
+            blocks.push(BloomFilter::from_keys(
+                keys[cursor..end].iter().map(|entry| entry.key.as_slice()),
+                end - cursor,
+                KEY_BLOOM_FP_RATE,
+            ));
+            cursor = end;
+            first_record += num_records;
+        }
+        Self {
+            file: BloomFilter::from_keys(
+                keys.iter().map(|entry| entry.key.as_slice()),
+                keys.len(),
+                KEY_BLOOM_FP_RATE,
+            ),
+            blocks,
+        }
+    }
+}
+
+/// name of the section that holds the ZoneMaps
+pub const ZONE_MAP_SECTION: &str = "gas.zonemap";
+
+/// min/max of every record statistic in each index block.
+/// (inf, -inf) means no record of the block has the statistic
+#[derive(Debug, Clone, Default, bincode::Encode, bincode::Decode)]
+pub struct ZoneMaps {
+    pub names: Vec<String>,
+    pub blocks: Vec<Vec<(f64, f64)>>,
+}
+
+impl ZoneMaps {
+    fn update(&mut self, block_zones: &mut Vec<(f64, f64)>, name: &str, value: f64) {
+        let idx = match self.names.iter().position(|n| n == name) {
+            Some(idx) => idx,
+            None => {
+                self.names.push(name.to_string());
+                self.names.len() - 1
+            }
+        };
+        if block_zones.len() <= idx {
+            block_zones.resize(idx + 1, (f64::INFINITY, f64::NEG_INFINITY));
+        }
+        let zone = &mut block_zones[idx];
+        zone.0 = zone.0.min(value);
+        zone.1 = zone.1.max(value);
+    }
+
+    /// whether some record of the block may match all the filters.
+    /// filters on statistics that are not recorded in the file can not rule out anything
+    pub fn may_match(&self, block_idx: usize, filters: &[StatFilter]) -> bool {
+        filters.iter().all(|filter| {
+            let Some(idx) = self.names.iter().position(|n| n == &filter.name) else {
+                return true;
+            };
+            let (min, max) = self.blocks[block_idx]
+                .get(idx)
+                .copied()
+                .unwrap_or((f64::INFINITY, f64::NEG_INFINITY));
+            filter.may_match(min, max)
+        })
+    }
+}
+
+/// a payload to be written, with the keys it can be looked up by (see GasFileReader::get_by_key)
+/// and numeric statistics that are aggregated into ZoneMaps (see GasFileReader::retain_blocks_matching)
+#[derive(Debug, Clone, Default)]
+pub struct GasRecord {
+    pub data: Vec<u8>,
+    pub keys: Vec<Vec<u8>>,
+    pub stats: Vec<(String, f64)>,
+    pub stored: bool, // data is already encoded with the codec of the writer, it is written as it is
+}
+
+impl GasRecord {
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            keys: vec![],
+            stats: vec![],
+            stored: false,
+        }
+    }
+
+    /// a payload read by GasRecordIter::stored, so copying it into a file with the same codec
+    /// skips the decompression and recompression
+    pub fn new_stored(data: Vec<u8>) -> Self {
+        Self {
+            stored: true,
+            ..Self::new(data)
+        }
+    }
+
+    pub fn with_key<K: Into<Vec<u8>>>(mut self, key: K) -> Self {
+        self.keys.push(key.into());
+        self
+    }
+
+    /// a statistic may be given several times, e.g. once per read of a batch
+    pub fn with_stat(mut self, name: &str, value: f64) -> Self {
+        self.stats.push((name.to_string(), value));
+        self
+    }
+}
+
+impl From<Vec<u8>> for GasRecord {
+    fn from(data: Vec<u8>) -> Self {
+        Self::new(data)
+    }
+}
+
 struct Locations {
     pub cur_position: u64,
     pub write_positions: WritePositions,
     pub write_positions_meta: WritePositionsMeta,
+    pub meta_len: u64, // serialized length of the write_positions_meta entries, the vec length is not counted
+    pub num_records: u64,
+    pub block_num_records: Vec<u64>,
+    pub keys: Vec<KeyEntry>,
+    pub zone_maps: ZoneMaps,
+    pub block_zones: Vec<(f64, f64)>, // zones of the block being written
 
     pub meta_cursor: usize, // this two cursor are for file reader, 下一个要处理哪块
     pub write_position_cursor: usize,
@@ -64,6 +275,12 @@ impl Locations {
             cur_position: cur_pos,
             write_positions: WritePositions::default(),
             write_positions_meta: WritePositionsMeta::default(),
+            meta_len: 0,
+            num_records: 0,
+            block_num_records: vec![],
+            keys: vec![],
+            zone_maps: ZoneMaps::default(),
+            block_zones: vec![],
             meta_cursor: 0,
             write_position_cursor: 0,
         }
@@ -83,7 +300,14 @@ impl Locations {
         locations.cur_position += serialize.len() as u64;
 
         let write_pos_and_serial = (write_pos, serialize.len() as u64);
+        locations.meta_len += bincode::encode_to_vec(write_pos_and_serial, cfg)
+            .unwrap()
+            .len() as u64;
         locations.write_positions_meta.push(write_pos_and_serial);
+        let block_num_records = locations.write_positions.len() as u64;
+        locations.block_num_records.push(block_num_records);
+        let block_zones = std::mem::take(&mut locations.block_zones);
+        locations.zone_maps.blocks.push(block_zones);
         locations.write_positions.clear();
         Some((write_pos, serialize))
     }
@@ -92,9 +316,15 @@ impl Locations {
 impl Default for Locations {
     fn default() -> Self {
         Self {
-            cur_position: 2 * 1024 * 1024 + 8, // 初始位置为2M + 8bytes
+            cur_position: DATA_OFFSET,
             write_positions: WritePositions(vec![]),
             write_positions_meta: WritePositionsMeta(vec![]),
+            meta_len: 0,
+            num_records: 0,
+            block_num_records: vec![],
+            keys: vec![],
+            zone_maps: ZoneMaps::default(),
+            block_zones: vec![],
             meta_cursor: 0,
             write_position_cursor: 0,
         }
@@ -110,6 +340,15 @@ impl From<WritePositionsMeta> for Locations {
 }
 
 const GAS_FILE_VERSION: u32 = 1;
+/// the version of files whose records are stored with a codec other than the plain one,
+/// so readers that don't know the codec reject them instead of returning the stored bytes
+const GAS_FILE_VERSION_CODEC: u32 = 2;
+/// 一级索引最多占用 2M
+pub(crate) const META_CAPACITY: u64 = 2 * 1024 * 1024;
+/// 数据区的起始位置为2M + 8bytes
+const DATA_OFFSET: u64 = META_CAPACITY + 8;
+/// the most bytes a varint u64 takes, so a (u64, u64) index entry takes at most twice as much
+const MAX_VARINT_LEN: u64 = 9;
 
 /// 存储序列化的对象，核心实现是二级存储
 /// 开头的 u32 存储 文件格式的版本，之后的 u32 存储的是 一级索引的长度，一级索引是 Vec<(usize, usize)> 序列化的结果。
@@ -117,43 +356,86 @@ const GAS_FILE_VERSION: u32 = 1;
 /// 之后每1000次写入会记录其每次写入的位置。所以gasfile 最多大概支持 32,000,000 次写入。
 /// ----file
 /// u32,u32,Vec<(usize, usize)>(2M+8bytes) .....(1000 write) positionsOfEachWrite
+/// 写完之后，sections 存放在最后，sections 的索引紧跟在一级索引之后: u32, Vec<(String, u64, u64)>
 pub struct GasFileWriter {
-    fname: path::PathBuf,
+    storage: Arc<dyn GasStorage>,
     threads: usize,
     barrier: Barrier,
 
     positions: Mutex<Locations>,
+    sections: Mutex<Vec<(String, Vec<u8>)>>,
+    codec: Mutex<RecordCodec>,
     worker_threads_started_flag: AtomicBool,
-    writer_recv: Receiver<Vec<u8>>,
+    writer_recv: Receiver<GasRecord>,
     handlers: Mutex<Option<Vec<thread::JoinHandle<()>>>>,
     writer_drop_barrier: Barrier,
+    error: Mutex<Option<String>>, // set when the file can't be completed, reported by wait_for_write_done
 }
 
 impl GasFileWriter {
     ///
     /// sender is used for to send data to be written. the data should be bytes stream
-    pub fn new_writer<P>(p: P, threads: NonZero<usize>) -> (Arc<Self>, Sender<Vec<u8>>)
+    /// a Vec<u8> can be sent with `.into()` when the record has no keys
+    pub fn new_writer<P>(p: P, threads: NonZero<usize>) -> (Arc<Self>, Sender<GasRecord>)
     where
         P: AsRef<Path>,
     {
-        let (sender, recv) = crossbeam::channel::bounded::<Vec<u8>>(1000);
+        let file = fs::File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(p.as_ref())
+            .unwrap_or_else(|err| panic!("create {:?} error. {}", p.as_ref(), err));
+        println!("create file success: {:?}", p.as_ref());
+        Self::with_storage(Arc::new(file), threads)
+    }
+
+    /// write to any storage, e.g. `SeekStorage<Cursor<Vec<u8>>>` for an in memory gas file
+    pub fn with_storage(
+        storage: Arc<dyn GasStorage>,
+        threads: NonZero<usize>,
+    ) -> (Arc<Self>, Sender<GasRecord>) {
+        let (sender, recv) = crossbeam::channel::bounded::<GasRecord>(1000);
 
-        let p = p.as_ref().to_owned();
         (
             Self {
-                fname: p.into(),
+                storage,
                 threads: threads.get(),
                 barrier: Barrier::new(threads.get()),
                 positions: Mutex::new(Locations::default()),
+                sections: Mutex::new(vec![]),
+                codec: Mutex::new(RecordCodec::default()),
                 worker_threads_started_flag: AtomicBool::new(false),
                 writer_recv: recv,
                 handlers: Mutex::new(Some(vec![])),
                 writer_drop_barrier: Barrier::new(2),
+                error: Mutex::new(None),
             }
             .into(),
             sender,
         )
     }
+
+    /// store a named blob in the file, a section with the same name is replaced.
+    /// sections are written when all the data are written, so it must be called before the sender is dropped
+    pub fn add_section(&self, name: &str, data: Vec<u8>) {
+        let mut sections = self.sections.lock().unwrap();
+        sections.retain(|(section_name, _)| section_name != name);
+        sections.push((name.to_string(), data));
+    }
+
+    /// compress and/or checksum every record. must be called before start_write_worker
+    pub fn set_codec(&self, codec: RecordCodec) {
+        assert!(
+            !self
+                .worker_threads_started_flag
+                .load(std::sync::atomic::Ordering::Relaxed),
+            "set_codec must be called before start_write_worker"
+        );
+        *self.codec.lock().unwrap() = codec;
+    }
+
     pub fn start_write_worker(self: &Arc<Self>) {
         if self
             .worker_threads_started_flag
@@ -164,12 +446,14 @@ impl GasFileWriter {
         self.worker_threads_started_flag
             .store(true, std::sync::atomic::Ordering::Relaxed);
 
-        let mut file = fs::File::create(&self.fname).unwrap();
-        println!("create file success: {:?}", &self.fname);
-        file.write_all(&GAS_FILE_VERSION.to_le_bytes()).unwrap();
-        file.flush().unwrap();
-        // file.set_len(1024 * 1024 * 1024 * 30).unwrap();
-        drop(file);
+        let version = if self.codec.lock().unwrap().is_plain() {
+            GAS_FILE_VERSION
+        } else {
+            GAS_FILE_VERSION_CODEC
+        };
+        self.storage
+            .write_all_at(&version.to_le_bytes(), 0)
+            .unwrap();
 
         for idx in 0..self.threads {
             let handler = {
@@ -188,13 +472,10 @@ impl GasFileWriter {
     }
 
     fn write_worker(self: &Arc<Self>, idx: usize) {
-        let mut file = fs::OpenOptions::new()
-            .write(true)
-            .open(&self.fname)
-            .unwrap();
         let recv = self.writer_recv.clone();
-        for data in recv {
-            self.write(&data, &mut file);
+        let codec = *self.codec.lock().unwrap();
+        for record in recv {
+            self.write(&record, &codec);
         }
         self.barrier.wait();
         if idx == 0 {
@@ -204,8 +485,7 @@ impl GasFileWriter {
                 if let Some((pos, serial)) =
                     Locations::compute_write_position_and_serial_of_write_positions(&mut locations)
                 {
-                    file.seek(std::io::SeekFrom::Start(pos)).unwrap();
-                    file.write_all(&serial).unwrap()
+                    self.storage.write_all_at(&serial, pos).unwrap();
                 }
             }
 
@@ -214,31 +494,112 @@ impl GasFileWriter {
             //     &self.positions.lock().unwrap().write_positions_meta
             // );
 
+            let sections = self.write_sections();
+            let sections_serialize = bincode::encode_to_vec(&sections, cfg).unwrap();
+
             let serialize =
                 bincode::encode_to_vec(&self.positions.lock().unwrap().write_positions_meta, cfg)
                     .unwrap();
             // println!("write_positions_meta_serial_len:{}", serialize.len());
-            file.seek(std::io::SeekFrom::Start(4)).unwrap();
-            file.write_all(&(serialize.len() as u32).to_le_bytes())
-                .unwrap();
-            file.seek(std::io::SeekFrom::Start(8)).unwrap();
-            file.write_all(&serialize).unwrap();
-            file.flush().unwrap();
+            let header_len = serialize.len() + 4 + sections_serialize.len();
+            if header_len as u64 > META_CAPACITY {
+                self.fail(format!(
+                    "too many writes for a single gas file, the index needs {} bytes but only {} bytes are reserved. consider RollingGasFileWriter",
+                    header_len, META_CAPACITY
+                ));
+            }
+            if self.error.lock().unwrap().is_some() {
+                self.writer_drop_barrier.wait();
+                return;
+            }
+            let mut header = (serialize.len() as u32).to_le_bytes().to_vec();
+            header.extend_from_slice(&serialize);
+            header.extend_from_slice(&(sections_serialize.len() as u32).to_le_bytes());
+            header.extend_from_slice(&sections_serialize);
+            self.storage.write_all_at(&header, 4).unwrap();
+            self.storage.flush().unwrap();
             self.writer_drop_barrier.wait();
         }
     }
 
-    fn write(self: &Arc<Self>, data: &[u8], file: &mut fs::File) {
+    /// the key index, the key bloom filters and the added sections are appended after the last index block
+    fn write_sections(&self) -> GasSections {
+        let mut locations = self.positions.lock().unwrap();
+        let mut pending = std::mem::take(&mut *self.sections.lock().unwrap());
+        if !locations.keys.is_empty() {
+            let mut keys = std::mem::take(&mut locations.keys);
+            let blooms = KeyBlooms::build(&keys, &locations.block_num_records);
+            let serialize = bincode::encode_to_vec(blooms, get_bincode_cfg()).unwrap();
+            pending.push((KEY_BLOOM_SECTION.to_string(), serialize));
+
+            keys.sort();
+            let serialize = bincode::encode_to_vec(KeyIndex(keys), get_bincode_cfg()).unwrap();
+            pending.push((KEY_INDEX_SECTION.to_string(), serialize));
+        }
+        if !locations.zone_maps.names.is_empty() {
+            let mut zone_maps = std::mem::take(&mut locations.zone_maps);
+            let num_names = zone_maps.names.len();
+            zone_maps
+                .blocks
+                .iter_mut()
+                .for_each(|zones| zones.resize(num_names, (f64::INFINITY, f64::NEG_INFINITY)));
+            let serialize = bincode::encode_to_vec(zone_maps, get_bincode_cfg()).unwrap();
+            pending.push((ZONE_MAP_SECTION.to_string(), serialize));
+        }
+
+        let codec = *self.codec.lock().unwrap();
+        if !codec.is_plain() {
+            let serialize = bincode::encode_to_vec(codec, get_bincode_cfg()).unwrap();
+            pending.push((CODEC_SECTION.to_string(), serialize));
+        }
+
+        let mut sections = GasSections::default();
+        for (name, data) in pending {
+            let pos = locations.cur_position;
+            locations.cur_position += data.len() as u64;
+            self.storage.write_all_at(&data, pos).unwrap();
+            sections.push((name, pos, data.len() as u64));
+        }
+        sections
+    }
+
+    fn write(self: &Arc<Self>, record: &GasRecord, codec: &RecordCodec) {
+        if self.error.lock().unwrap().is_some() {
+            return;
+        }
+        let data = if record.stored {
+            Cow::Borrowed(record.data.as_slice())
+        } else {
+            codec.encode(&record.data)
+        };
         let cur_pos = {
             let mut locations = self.positions.lock().unwrap();
             let cur_pos = locations.cur_position;
             locations.cur_position += data.len() as u64;
             locations.write_positions.push(cur_pos);
+
+            let record_idx = locations.num_records;
+            locations.num_records += 1;
+            for key in &record.keys {
+                locations.keys.push(KeyEntry {
+                    key: key.clone(),
+                    record_idx,
+                    position: cur_pos,
+                    len: data.len() as u64,
+                });
+            }
+            let Locations {
+                zone_maps,
+                block_zones,
+                ..
+            } = &mut *locations;
+            for (name, value) in &record.stats {
+                zone_maps.update(block_zones, name, *value);
+            }
             cur_pos
         };
 
-        file.seek(std::io::SeekFrom::Start(cur_pos as u64)).unwrap();
-        file.write_all(data).unwrap();
+        self.storage.write_all_at(&data, cur_pos).unwrap();
 
         let value2write = {
             let mut locations = self.positions.lock().unwrap();
@@ -253,26 +614,91 @@ impl GasFileWriter {
                 None
             };
 
+            if value2write.is_some() && !self.index_has_room(&locations, 0) {
+                self.fail(format!(
+                    "too many writes for a single gas file, the index exceeds the {} bytes reserved for it after {} records. consider RollingGasFileWriter",
+                    META_CAPACITY, locations.num_records
+                ));
+            }
             value2write
         };
 
         if let Some((write_pos, serialize)) = value2write {
-            file.seek(std::io::SeekFrom::Start(write_pos as u64))
-                .unwrap();
-            file.write_all(&serialize).unwrap();
+            self.storage.write_all_at(&serialize, write_pos).unwrap();
         }
     }
 
+    /// whether the index can hold `num_records` records in total. the blocks written so far are
+    /// counted as they are, the rest as if every index entry takes the most bytes it can.
+    /// RollingGasFileWriter asks it to start a new file before the index is full
+    pub fn has_room_for(&self, num_records: u64) -> bool {
+        let locations = self.positions.lock().unwrap();
+        let in_blocks = locations.block_num_records.iter().sum::<u64>();
+        self.index_has_room(
+            &locations,
+            num_records.saturating_sub(in_blocks).div_ceil(1000),
+        )
+    }
+
+    /// whether the index fits in META_CAPACITY with `num_blocks` more blocks and all the sections
+    fn index_has_room(&self, locations: &Locations, num_blocks: u64) -> bool {
+        let entry_len = |name: &str| MAX_VARINT_LEN * 3 + name.len() as u64;
+        let sections_len = MAX_VARINT_LEN
+            + self
+                .sections
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(name, _)| entry_len(name))
+                .sum::<u64>()
+            + [
+                KEY_BLOOM_SECTION,
+                KEY_INDEX_SECTION,
+                ZONE_MAP_SECTION,
+                CODEC_SECTION,
+            ]
+            .map(entry_len)
+            .iter()
+            .sum::<u64>();
+        let meta_len = MAX_VARINT_LEN + locations.meta_len + num_blocks * MAX_VARINT_LEN * 2;
+        meta_len + 4 + sections_len <= META_CAPACITY
+    }
+
+    fn fail(&self, err: String) {
+        self.error.lock().unwrap().get_or_insert(err);
+    }
+
+    /// panics if the file could not be completed, e.g. its index outgrew META_CAPACITY
     pub fn wait_for_write_done(self: Arc<Self>) {
         self.writer_drop_barrier.wait();
+        if let Some(err) = self.error.lock().unwrap().take() {
+            panic!("{}", err);
+        }
     }
 }
 
 pub struct GasFileReader {
     threads: usize,
-    fname: path::PathBuf,
+    fname: Option<PathBuf>,
+    storage: Arc<dyn GasStorage>,
+    blocks: WritePositionsMeta, // all index blocks of the file, positions holds the ones to be read
+    sections: GasSections,
+    codec: RecordCodec,
     positions: Mutex<Locations>,
     read_sender: Mutex<Option<Sender<Vec<u8>>>>,
+
+    record_offsets: OnceLock<Vec<u64>>, // index of the first record of each block, plus the total count
+    random_access: Mutex<Option<RandomAccessCache>>,
+    key_index: OnceLock<Option<KeyIndex>>,
+    key_order: OnceLock<Vec<usize>>, // key_index entries ordered by record, see GasFileReader::record_keys
+    key_blooms: OnceLock<Option<KeyBlooms>>,
+    zone_maps: OnceLock<Option<ZoneMaps>>,
+}
+
+/// the most recently used index block of GasFileReader::get
+struct RandomAccessCache {
+    block_idx: usize,
+    write_positions: WritePositions,
 }
 
 impl GasFileReader {
@@ -281,44 +707,351 @@ impl GasFileReader {
         P: AsRef<Path>,
     {
         let p = p.as_ref().to_owned();
-        let mut file = fs::File::open(&p).unwrap();
+        let file = fs::File::open(&p).unwrap_or_else(|err| panic!("open {:?} error. {}", p, err));
+        Self::open(Some(p), Arc::new(file), threads)
+    }
+
+    /// read from any storage, e.g. a gas file written by GasFileWriter::with_storage
+    pub fn with_storage(
+        storage: Arc<dyn GasStorage>,
+        threads: NonZero<usize>,
+    ) -> (Arc<Self>, Receiver<Vec<u8>>) {
+        Self::open(None, storage, threads)
+    }
+
+    fn open(
+        fname: Option<PathBuf>,
+        storage: Arc<dyn GasStorage>,
+        threads: NonZero<usize>,
+    ) -> (Arc<Self>, Receiver<Vec<u8>>) {
         let mut version_bytes = [0u8; 4];
-        file.read_exact(&mut version_bytes).unwrap();
+        storage.read_exact_at(&mut version_bytes, 0).unwrap();
         let version = u32::from_le_bytes(version_bytes);
 
         let mut meta_len = [0u8; 4];
-        file.seek(std::io::SeekFrom::Start(4)).unwrap();
-        file.read_exact(&mut meta_len).unwrap();
+        storage.read_exact_at(&mut meta_len, 4).unwrap();
         let meta_len = u32::from_le_bytes(meta_len);
         // println!("version:{}, metalen:{}", version, meta_len);
-        file.seek(std::io::SeekFrom::Start(8)).unwrap();
         let mut positions_meta = vec![0_u8; meta_len as usize];
-        file.read_exact(&mut positions_meta).unwrap();
+        storage.read_exact_at(&mut positions_meta, 8).unwrap();
 
         let (write_positions_meta, nbytes): (WritePositionsMeta, usize) =
             bincode::decode_from_slice(&positions_meta, get_bincode_cfg()).unwrap();
         assert_eq!(nbytes, meta_len as usize);
 
+        // files written before sections were introduced have zeros (or nothing) here
+        let mut sections_len = [0u8; 4];
+        let sections_pos = 8 + meta_len as u64;
+        let sections = match storage.read_exact_at(&mut sections_len, sections_pos) {
+            Ok(()) if u32::from_le_bytes(sections_len) > 0 => {
+                let mut sections = vec![0_u8; u32::from_le_bytes(sections_len) as usize];
+                storage
+                    .read_exact_at(&mut sections, sections_pos + 4)
+                    .unwrap();
+                bincode::decode_from_slice(&sections, get_bincode_cfg())
+                    .unwrap()
+                    .0
+            }
+            Ok(()) => GasSections::default(),
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => GasSections::default(),
+            Err(err) => panic!("read gas sections error. {}", err),
+        };
+
+        assert!(
+            version == GAS_FILE_VERSION || version == GAS_FILE_VERSION_CODEC,
+            "Unsupported gas file version. expected {} or {}, found {}",
+            GAS_FILE_VERSION,
+            GAS_FILE_VERSION_CODEC,
+            version
+        );
+
+        let codec = match sections.iter().find(|(name, _, _)| name == CODEC_SECTION) {
+            Some(&(_, pos, len)) => {
+                let data = read_record(storage.as_ref(), pos, len);
+                bincode::decode_from_slice(&data, get_bincode_cfg())
+                    .unwrap()
+                    .0
+            }
+            None => RecordCodec::default(),
+        };
         assert_eq!(
-            version, GAS_FILE_VERSION,
-            "Unsupported gas file version. expected {}, found {}",
-            GAS_FILE_VERSION, version
+            codec.is_plain(),
+            version == GAS_FILE_VERSION,
+            "invalid gas file. version {} with codec {:?}",
+            version,
+            codec
         );
 
         let (sender, recv) = crossbeam::channel::bounded(1000);
 
         (
             Self {
-                fname: p.into(),
+                fname,
+                storage,
                 threads: threads.get(),
-                positions: Mutex::new(write_positions_meta.into()),
+                positions: Mutex::new(write_positions_meta.clone().into()),
+                blocks: write_positions_meta,
+                sections,
+                codec,
                 read_sender: Mutex::new(sender.into()),
+                record_offsets: OnceLock::new(),
+                random_access: Mutex::new(None),
+                key_index: OnceLock::new(),
+                key_order: OnceLock::new(),
+                key_blooms: OnceLock::new(),
+                zone_maps: OnceLock::new(),
             }
             .into(),
             recv,
         )
     }
 
+    /// None if the reader is created with_storage
+    pub fn path(&self) -> Option<&Path> {
+        self.fname.as_deref()
+    }
+
+    /// how the records of the file are stored, see GasFileWriter::set_codec
+    pub fn codec(&self) -> RecordCodec {
+        self.codec
+    }
+
+    pub fn section_names(&self) -> Vec<&str> {
+        self.sections
+            .iter()
+            .map(|(name, _, _)| name.as_str())
+            .collect()
+    }
+
+    /// sections that are not maintained by the writer itself, tools copy them to their outputs
+    pub fn user_sections(&self) -> Vec<&str> {
+        self.section_names()
+            .into_iter()
+            .filter(|name| !name.starts_with(RESERVED_SECTION_PREFIX))
+            .collect()
+    }
+
+    /// read a section added by GasFileWriter::add_section
+    pub fn section(&self, name: &str) -> Option<Vec<u8>> {
+        let &(_, pos, len) = self
+            .sections
+            .iter()
+            .find(|(section_name, _, _)| section_name == name)?;
+        Some(read_record(self.storage.as_ref(), pos, len))
+    }
+
+    /// the key -> record table, None if the records are written without keys.
+    /// the table is loaded on the first call
+    pub fn key_index(&self) -> Option<&KeyIndex> {
+        self.key_index
+            .get_or_init(|| {
+                self.section(KEY_INDEX_SECTION).map(|data| {
+                    bincode::decode_from_slice(&data, get_bincode_cfg())
+                        .unwrap()
+                        .0
+                })
+            })
+            .as_ref()
+    }
+
+    /// the bloom filters over the record keys, None if the records are written without keys.
+    /// they are loaded on the first call
+    pub fn key_blooms(&self) -> Option<&KeyBlooms> {
+        self.key_blooms
+            .get_or_init(|| {
+                self.section(KEY_BLOOM_SECTION).map(|data| {
+                    bincode::decode_from_slice(&data, get_bincode_cfg())
+                        .unwrap()
+                        .0
+                })
+            })
+            .as_ref()
+    }
+
+    /// false means no record of the file has the key. only the bloom filter is consulted,
+    /// files without it always may contain the key
+    pub fn may_contain_key(&self, key: &[u8]) -> bool {
+        self.key_blooms()
+            .is_none_or(|blooms| blooms.file.may_contain(key))
+    }
+
+    /// the first record written with the key, O(log n) without scanning the records
+    pub fn get_by_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if !self.may_contain_key(key) {
+            return None;
+        }
+        let entry = self.key_index()?.find(key).first()?;
+        Some(self.codec.decode(read_record(
+            self.storage.as_ref(),
+            entry.position,
+            entry.len,
+        )))
+    }
+
+    /// every record written with the key, in record order
+    pub fn get_all_by_key(&self, key: &[u8]) -> Vec<Vec<u8>> {
+        let Some(key_index) = self.key_index() else {
+            return vec![];
+        };
+        key_index
+            .find(key)
+            .iter()
+            .map(|entry| {
+                self.codec.decode(read_record(
+                    self.storage.as_ref(),
+                    entry.position,
+                    entry.len,
+                ))
+            })
+            .collect()
+    }
+
+    /// number of records in the whole file, shard is not taken into account.
+    /// the first call reads every index block
+    pub fn num_records(&self) -> u64 {
+        *self.record_offsets().last().unwrap()
+    }
+
+    fn record_offsets(&self) -> &Vec<u64> {
+        self.record_offsets.get_or_init(|| {
+            let mut offsets = vec![0];
+            for &block in self.blocks.iter() {
+                // the block position is appended to the end of write positions
+                let num = read_write_positions(self.storage.as_ref(), block).len() as u64 - 1;
+                offsets.push(offsets.last().unwrap() + num);
+            }
+            offsets
+        })
+    }
+
+    /// index of the block holding the `n`-th record of the file, None if out of range
+    pub fn block_of_record(&self, n: u64) -> Option<usize> {
+        let offsets = self.record_offsets();
+        if n >= *offsets.last().unwrap() {
+            return None;
+        }
+        Some(offsets.partition_point(|&offset| offset <= n) - 1)
+    }
+
+    /// random access to the `n`-th record of the file. shard is not taken into account.
+    /// the last used index block is cached, so ascending `n` only reads the payloads
+    pub fn get(&self, n: u64) -> Option<Vec<u8>> {
+        self.get_stored(n).map(|stored| self.codec.decode(stored))
+    }
+
+    /// like get, but the bytes as they are stored, still compressed and with the checksum
+    pub fn get_stored(&self, n: u64) -> Option<Vec<u8>> {
+        let block_idx = self.block_of_record(n)?;
+        let offsets = self.record_offsets();
+
+        let mut cache = self.random_access.lock().unwrap();
+        let cache = cache.get_or_insert_with(|| RandomAccessCache {
+            block_idx: usize::MAX,
+            write_positions: WritePositions::default(),
+        });
+        if cache.block_idx != block_idx {
+            cache.write_positions =
+                read_write_positions(self.storage.as_ref(), self.blocks[block_idx]);
+            cache.block_idx = block_idx;
+        }
+
+        let idx = (n - offsets[block_idx]) as usize;
+        let start = cache.write_positions[idx];
+        let len = cache.write_positions[idx + 1] - start;
+        Some(read_record(self.storage.as_ref(), start, len))
+    }
+
+    /// restrict the reader to the `rank`-th of `world_size` disjoint shards.
+    /// index blocks are split into contiguous runs holding roughly the same number of bytes,
+    /// a block belongs to the shard its midpoint falls into. so the union over all ranks covers every record exactly once.
+    /// must be called before start_read_worker
+    pub fn shard(&self, rank: usize, world_size: usize) {
+        assert!(
+            rank < world_size,
+            "invalid shard. rank:{}, world_size:{}",
+            rank,
+            world_size
+        );
+        let spans = self.blocks.block_spans();
+        let tot_bytes = spans.last().map(|&(_, end)| end - DATA_OFFSET).unwrap_or(0);
+
+        let selected = self
+            .blocks
+            .iter()
+            .zip(spans)
+            .filter(|(_, (start, end))| {
+                let mid = (start - DATA_OFFSET) + (end - start) / 2;
+                (mid as u128 * world_size as u128 / tot_bytes as u128) as usize == rank
+            })
+            .map(|(block, _)| *block)
+            .collect::<Vec<_>>();
+
+        *self.positions.lock().unwrap() = WritePositionsMeta(selected).into();
+    }
+
+    /// keep only the index blocks that may hold a record with one of the keys, judged by the block bloom filters.
+    /// like shard, it must be called before reading. nothing is dropped if the file has no bloom filter.
+    /// returns the number of records in the dropped blocks
+    pub fn retain_blocks_with_keys(&self, keys: &[&[u8]]) -> u64 {
+        let Some(blooms) = self.key_blooms() else {
+            return 0;
+        };
+        self.retain_blocks(|block_idx| {
+            keys.iter()
+                .any(|key| blooms.blocks[block_idx].may_contain(key))
+        })
+    }
+
+    /// the per block min/max of the record statistics, None if the records are written without statistics.
+    /// they are loaded on the first call
+    pub fn zone_maps(&self) -> Option<&ZoneMaps> {
+        self.zone_maps
+            .get_or_init(|| {
+                self.section(ZONE_MAP_SECTION).map(|data| {
+                    bincode::decode_from_slice(&data, get_bincode_cfg())
+                        .unwrap()
+                        .0
+                })
+            })
+            .as_ref()
+    }
+
+    /// keep only the index blocks whose zone maps say some record may match all the filters,
+    /// e.g. `rq>=0.99`. records without the statistic never match. the records of the kept blocks
+    /// still need to be checked one by one. like shard, it must be called before reading.
+    /// returns the number of records in the dropped blocks
+    pub fn retain_blocks_matching(&self, filters: &[StatFilter]) -> u64 {
+        let Some(zone_maps) = self.zone_maps() else {
+            return 0;
+        };
+        self.retain_blocks(|block_idx| zone_maps.may_match(block_idx, filters))
+    }
+
+    /// filter the blocks to be read by their index in the file, returns the number of records dropped
+    fn retain_blocks<F>(&self, f: F) -> u64
+    where
+        F: Fn(usize) -> bool,
+    {
+        let mut positions = self.positions.lock().unwrap();
+        let mut num_dropped = 0;
+        let selected = positions
+            .write_positions_meta
+            .iter()
+            .filter(|block| {
+                let block_idx = self.blocks.partition_point(|b| b.0 < block.0);
+                let keep = f(block_idx);
+                if !keep {
+                    let offsets = self.record_offsets();
+                    num_dropped += offsets[block_idx + 1] - offsets[block_idx];
+                }
+                keep
+            })
+            .copied()
+            .collect();
+        *positions = WritePositionsMeta(selected).into();
+        num_dropped
+    }
+
     pub fn start_read_worker(self: &Arc<Self>) {
         let sender = self.read_sender.lock().unwrap().take();
         if let Some(sender) = sender {
@@ -337,8 +1070,7 @@ impl GasFileReader {
     }
 
     pub fn read_worker(self: Arc<Self>, sender: Sender<Vec<u8>>) {
-        let mut file = fs::File::open(&self.fname).unwrap();
-        while let Some(data) = self.read(&mut file) {
+        while let Some(data) = self.read() {
             sender.send(data).unwrap();
         }
         for _ in 0..self.threads {
@@ -346,8 +1078,7 @@ impl GasFileReader {
                 let reader = Arc::clone(&self);
                 let sender = sender.clone();
                 move || {
-                    let mut file = fs::File::open(&reader.fname).unwrap();
-                    while let Some(data) = reader.read(&mut file) {
+                    while let Some(data) = reader.read() {
                         sender.send(data).unwrap();
                     }
                 }
@@ -355,7 +1086,7 @@ impl GasFileReader {
         }
     }
 
-    pub fn read(self: &Arc<Self>, file: &mut fs::File) -> Option<Vec<u8>> {
+    pub fn read(self: &Arc<Self>) -> Option<Vec<u8>> {
         let (start, len) = {
             let mut position = self.positions.lock().unwrap();
 
@@ -367,16 +1098,8 @@ impl GasFileReader {
                 if position.meta_cursor >= position.write_positions_meta.len() {
                     return None;
                 }
-                let (start, len) = position.write_positions_meta[position.meta_cursor];
-                file.seek(std::io::SeekFrom::Start(start)).unwrap();
-                let mut buf = vec![0; len as usize];
-                file.read_exact(&mut buf).unwrap();
-                let (mut write_positions, nbytes): (WritePositions, usize) =
-                    bincode::decode_from_slice(&buf, get_bincode_cfg()).unwrap();
-                write_positions.push(start);
-                position.write_positions = write_positions;
-
-                assert_eq!(nbytes, len as usize);
+                let block = position.write_positions_meta[position.meta_cursor];
+                position.write_positions = read_write_positions(self.storage.as_ref(), block);
                 position.write_position_cursor = 0;
                 position.meta_cursor += 1;
             }
@@ -388,22 +1111,249 @@ impl GasFileReader {
 
             (start, len)
         };
-        let mut buf = vec![0; len as usize];
-        file.seek(std::io::SeekFrom::Start(start)).unwrap();
-        file.read_exact(&mut buf).unwrap();
+        Some(
+            self.codec
+                .decode(read_record(self.storage.as_ref(), start, len)),
+        )
+    }
+
+    /// iterate the records in file order.
+    /// unlike the read workers, the order is deterministic. honors shard
+    pub fn records(&self) -> RecordIter {
+        RecordIter::new(
+            Arc::clone(&self.storage),
+            self.positions.lock().unwrap().write_positions_meta.to_vec(),
+            self.codec,
+        )
+    }
+
+    /// iterate the records of the whole file in order, with the keys they are written with.
+    /// zone maps only keep the per block min/max, so each record carries the bounds of its block as stats,
+    /// writing them into another file keeps the zone maps there conservative. shard is not taken into account
+    pub fn gas_records(self: &Arc<Self>) -> GasRecordIter {
+        self.select_gas_records(0..self.num_records())
+    }
+
+    /// like gas_records, but only the records at the given indices. ascending indices read
+    /// each index block once and skip the payloads that are not selected.
+    /// the indices are consumed lazily
+    pub fn select_gas_records<I>(self: &Arc<Self>, indices: I) -> GasRecordIter
+    where
+        I: IntoIterator<Item = u64>,
+        I::IntoIter: Send + 'static,
+    {
+        GasRecordIter {
+            reader: Arc::clone(self),
+            indices: Box::new(indices.into_iter()),
+            stored: false,
+        }
+    }
+
+    /// the keys the record is written with, O(log n) in the number of keys.
+    /// the first call orders the key index by record
+    fn record_keys(&self, record_idx: u64) -> Vec<Vec<u8>> {
+        let Some(key_index) = self.key_index() else {
+            return vec![];
+        };
+        let key_order = self.key_order.get_or_init(|| {
+            let mut key_order = (0..key_index.len()).collect::<Vec<_>>();
+            // stable, so the keys of a record stay sorted
+            key_order.sort_by_key(|&i| key_index[i].record_idx);
+            key_order
+        });
+        let start = key_order.partition_point(|&i| key_index[i].record_idx < record_idx);
+        key_order[start..]
+            .iter()
+            .map(|&i| &key_index[i])
+            .take_while(|entry| entry.record_idx == record_idx)
+            .map(|entry| entry.key.clone())
+            .collect()
+    }
+
+    /// iterate the records in a shuffled order that is reproducible for the same seed and epoch.
+    /// the draws are made from the raw ChaCha8 stream, so the order does not depend on how rand
+    /// shuffles or samples a range.
+    /// index blocks are visited in a random order so the reads stay sequential inside a block,
+    /// records are further shuffled within a buffer holding at most `buffer_size` records
+    pub fn shuffled_records(
+        &self,
+        seed: u64,
+        epoch: u64,
+        buffer_size: usize,
+    ) -> ShuffledRecordIter {
+        let mut rng_seed = [0_u8; 32];
+        rng_seed[..8].copy_from_slice(&seed.to_le_bytes());
+        rng_seed[8..16].copy_from_slice(&epoch.to_le_bytes());
+        let mut rng = ChaCha8Rng::from_seed(rng_seed);
+
+        let mut blocks = self.positions.lock().unwrap().write_positions_meta.to_vec();
+        // fisher-yates
+        for i in (1..blocks.len()).rev() {
+            blocks.swap(i, below(&mut rng, i + 1));
+        }
+
+        ShuffledRecordIter {
+            records: RecordIter::new(Arc::clone(&self.storage), blocks, self.codec),
+            buffer: Vec::with_capacity(buffer_size),
+            buffer_size: buffer_size.max(1),
+            rng,
+        }
+    }
+}
+
+/// read an index block, the block position is appended as the end of its last record
+fn read_write_positions(storage: &dyn GasStorage, (start, len): (u64, u64)) -> WritePositions {
+    let mut buf = vec![0; len as usize];
+    storage.read_exact_at(&mut buf, start).unwrap();
+    let (mut write_positions, nbytes): (WritePositions, usize) =
+        bincode::decode_from_slice(&buf, get_bincode_cfg()).unwrap();
+    assert_eq!(nbytes, len as usize);
+    write_positions.push(start);
+    write_positions
+}
+
+fn read_record(storage: &dyn GasStorage, start: u64, len: u64) -> Vec<u8> {
+    let mut buf = vec![0; len as usize];
+    storage.read_exact_at(&mut buf, start).unwrap();
+    buf
+}
+
+/// sequentially reads the records of the given index blocks
+pub struct RecordIter {
+    storage: Arc<dyn GasStorage>,
+    blocks: Vec<(u64, u64)>,
+    block_cursor: usize,
+    write_positions: WritePositions,
+    write_position_cursor: usize,
+    codec: RecordCodec,
+}
+
+impl RecordIter {
+    fn new(storage: Arc<dyn GasStorage>, blocks: Vec<(u64, u64)>, codec: RecordCodec) -> Self {
+        Self {
+            storage,
+            blocks,
+            codec,
+            block_cursor: 0,
+            write_positions: WritePositions::default(),
+            write_position_cursor: 0,
+        }
+    }
+}
+
+impl Iterator for RecordIter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.write_position_cursor + 1 >= self.write_positions.len() {
+            let block = *self.blocks.get(self.block_cursor)?;
+            self.write_positions = read_write_positions(self.storage.as_ref(), block);
+            self.write_position_cursor = 0;
+            self.block_cursor += 1;
+        }
+        let start = self.write_positions[self.write_position_cursor];
+        let len = self.write_positions[self.write_position_cursor + 1] - start;
+        self.write_position_cursor += 1;
+        Some(
+            self.codec
+                .decode(read_record(self.storage.as_ref(), start, len)),
+        )
+    }
+}
+
+/// see GasFileReader::gas_records
+pub struct GasRecordIter {
+    reader: Arc<GasFileReader>,
+    indices: Box<dyn Iterator<Item = u64> + Send>,
+    stored: bool,
+}
+
+impl GasRecordIter {
+    /// yield the payloads as they are stored, see GasRecord::new_stored.
+    /// the records must go to a writer with the same codec as the reader, checksums are not verified
+    pub fn stored(mut self) -> Self {
+        self.stored = true;
+        self
+    }
+}
+
+impl Iterator for GasRecordIter {
+    type Item = GasRecord;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = self.indices.next()?;
+        let data = self.reader.get_stored(idx).unwrap_or_else(|| {
+            panic!(
+                "record index {} out of range, the file has {} records",
+                idx,
+                self.reader.num_records()
+            )
+        });
+
+        let mut record = if self.stored {
+            GasRecord::new_stored(data)
+        } else {
+            GasRecord::new(self.reader.codec.decode(data))
+        };
+        record.keys = self.reader.record_keys(idx);
+        if let Some(zone_maps) = self.reader.zone_maps() {
+            let block_idx = self.reader.block_of_record(idx).unwrap();
+            for (name, &(min, max)) in zone_maps.names.iter().zip(&zone_maps.blocks[block_idx]) {
+                if min <= max {
+                    record = record.with_stat(name, min).with_stat(name, max);
+                }
+            }
+        }
+        Some(record)
+    }
+}
 
-        Some(buf)
+/// shuffle buffer on top of a RecordIter whose blocks are already shuffled
+pub struct ShuffledRecordIter {
+    records: RecordIter,
+    buffer: Vec<Vec<u8>>,
+    buffer_size: usize,
+    rng: ChaCha8Rng,
+}
+
+impl Iterator for ShuffledRecordIter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.len() < self.buffer_size {
+            match self.records.next() {
+                Some(record) => self.buffer.push(record),
+                None => break,
+            }
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let idx = below(&mut self.rng, self.buffer.len());
+        Some(self.buffer.swap_remove(idx))
     }
 }
 
+/// uniform in [0, n), by the high bits of a widening multiply
+fn below(rng: &mut ChaCha8Rng, n: usize) -> usize {
+    ((rng.next_u64() as u128 * n as u128) >> 64) as usize
+}
+
 #[cfg(test)]
 mod test {
-    use std::num::NonZero;
+    use std::{fs, io::Cursor, num::NonZero, sync::Arc};
 
     use gskits::ds::ReadInfo;
     use tempfile::NamedTempFile;
 
-    use super::{GasFileReader, GasFileWriter, get_bincode_cfg};
+    use super::{
+        GAS_FILE_VERSION, GAS_FILE_VERSION_CODEC, GasFileReader, GasFileWriter, GasRecord,
+        META_CAPACITY, get_bincode_cfg,
+    };
+    use crate::io::{
+        codec::{Checksum, Compression, RecordCodec},
+        storage::SeekStorage,
+    };
 
     #[test]
     fn test_gas_rw() {
@@ -413,7 +1363,7 @@ mod test {
         writer.start_write_worker();
         for i in 0_u32..33559 {
             sender
-                .send(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap())
+                .send(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap().into())
                 .unwrap();
         }
         drop(sender);
@@ -438,4 +1388,359 @@ mod test {
         println!("{:?}", results);
     }
 
+    #[test]
+    fn test_gas_shard() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(2).unwrap());
+        writer.start_write_worker();
+        for i in 0_u32..10_357 {
+            sender
+                .send(
+                    bincode::encode_to_vec(vec![i; (i % 7) as usize + 1], get_bincode_cfg())
+                        .unwrap()
+                        .into(),
+                )
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let world_size = 3;
+        let mut results = vec![];
+        for rank in 0..world_size {
+            let (reader, recv) =
+                GasFileReader::new_reader(named_file.path(), NonZero::new(2).unwrap());
+            reader.shard(rank, world_size);
+            reader.start_read_worker();
+            let shard = recv
+                .into_iter()
+                .map(|v| {
+                    let (v, _nbytes): (Vec<u32>, usize) =
+                        bincode::decode_from_slice(&v, get_bincode_cfg()).unwrap();
+                    v[0]
+                })
+                .collect::<Vec<_>>();
+            assert!(!shard.is_empty());
+            results.extend(shard);
+        }
+
+        results.sort();
+        assert_eq!(results, (0_u32..10_357).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_gas_in_memory_storage() {
+        let storage = Arc::new(SeekStorage::new(Cursor::new(vec![])));
+        // one write worker, so the records are stored in the order they are sent
+        let (writer, sender) =
+            GasFileWriter::with_storage(storage.clone(), NonZero::new(1).unwrap());
+        writer.add_section("user.note", b"hello".to_vec());
+        writer.start_write_worker();
+        for i in 0_u32..2_500 {
+            sender
+                .send(GasRecord::new(i.to_le_bytes().to_vec()).with_key(i.to_string()))
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (reader, _recv) = GasFileReader::with_storage(storage, NonZero::new(1).unwrap());
+        assert_eq!(reader.path(), None);
+        assert_eq!(reader.num_records(), 2_500);
+        assert_eq!(reader.section("user.note"), Some(b"hello".to_vec()));
+        assert!(
+            reader
+                .records()
+                .enumerate()
+                .all(|(i, data)| data == (i as u32).to_le_bytes())
+        );
+        assert_eq!(reader.get(1_234), Some(1_234_u32.to_le_bytes().to_vec()));
+        assert_eq!(
+            reader.get_by_key(b"2499"),
+            Some(2_499_u32.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn test_gas_keys_and_sections() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(2).unwrap());
+        writer.start_write_worker();
+        writer.add_section("user.note", b"hello".to_vec());
+        for i in 0_u32..3_000 {
+            let record = GasRecord::new(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap())
+                .with_key(format!("read/{}", i))
+                .with_key(format!("zmw/{}", i / 2));
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (reader, _recv) =
+            GasFileReader::new_reader(named_file.path(), NonZero::new(1).unwrap());
+        let decode =
+            |v: Vec<u8>| -> u32 { bincode::decode_from_slice(&v, get_bincode_cfg()).unwrap().0 };
+        assert_eq!(reader.section("user.note"), Some(b"hello".to_vec()));
+        assert_eq!(reader.section("user.missing"), None);
+
+        for i in [0_u32, 1, 999, 1_000, 2_999] {
+            assert_eq!(
+                reader
+                    .get_by_key(format!("read/{}", i).as_bytes())
+                    .map(decode),
+                Some(i)
+            );
+            let entry = &reader
+                .key_index()
+                .unwrap()
+                .find(format!("read/{}", i).as_bytes())[0];
+            assert_eq!(reader.get(entry.record_idx).map(decode), Some(i));
+        }
+        let mut zmw = reader
+            .get_all_by_key(b"zmw/700")
+            .into_iter()
+            .map(decode)
+            .collect::<Vec<_>>();
+        zmw.sort();
+        assert_eq!(zmw, vec![1_400, 1_401]);
+        assert_eq!(reader.get_by_key(b"read/3000"), None);
+
+        let indices = [1_u64, 1_500, 2_999];
+        let selected = reader.select_gas_records(indices).collect::<Vec<_>>();
+        assert_eq!(selected.len(), indices.len());
+        for (idx, record) in indices.into_iter().zip(selected) {
+            let i = decode(record.data);
+            assert_eq!(reader.get(idx).map(decode), Some(i));
+            assert_eq!(
+                record.keys,
+                vec![
+                    format!("read/{}", i).into_bytes(),
+                    format!("zmw/{}", i / 2).into_bytes()
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_gas_index_capacity() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(2).unwrap());
+        // the section table takes most of the header, so the file indexes a few tens of blocks
+        writer.add_section(&"x".repeat(META_CAPACITY as usize - 1_000), vec![]);
+        writer.start_write_worker();
+        assert!(writer.has_room_for(10_000));
+        assert!(!writer.has_room_for(1_000_000));
+        for i in 0_u32..200_000 {
+            sender.send(i.to_le_bytes().to_vec().into()).unwrap();
+        }
+        drop(sender);
+        // the error comes from wait_for_write_done instead of hanging it
+        let err = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            writer.wait_for_write_done()
+        }))
+        .unwrap_err();
+        let err = err.downcast_ref::<String>().unwrap();
+        assert!(err.contains("too many writes"), "{}", err);
+    }
+
+    #[test]
+    #[should_panic(expected = "version 1 with codec")]
+    fn test_gas_codec_version() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(1).unwrap());
+        writer.set_codec(RecordCodec {
+            compression: Compression::Zstd { level: 3 },
+            checksum: Checksum::None,
+        });
+        writer.start_write_worker();
+        sender.send(GasRecord::new(vec![1, 2, 3])).unwrap();
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let mut data = fs::read(named_file.path()).unwrap();
+        data[..4].copy_from_slice(&GAS_FILE_VERSION.to_le_bytes());
+        fs::write(named_file.path(), data).unwrap();
+        GasFileReader::new_reader(named_file.path(), NonZero::new(1).unwrap());
+    }
+
+    #[test]
+    fn test_gas_codec() {
+        let named_file = NamedTempFile::new().unwrap();
+        let codec = RecordCodec {
+            compression: Compression::Zstd { level: 3 },
+            checksum: Checksum::Crc32,
+        };
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(1).unwrap());
+        writer.set_codec(codec);
+        writer.start_write_worker();
+        for i in 0_u32..1_500 {
+            let data = format!("ACGT{}", i).repeat(50).into_bytes();
+            sender
+                .send(GasRecord::new(data).with_key(i.to_string()))
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        // older readers know the version 1 only, so they reject the file
+        let version = fs::read(named_file.path()).unwrap()[..4].to_vec();
+        assert_eq!(version, GAS_FILE_VERSION_CODEC.to_le_bytes());
+
+        let (reader, _recv) =
+            GasFileReader::new_reader(named_file.path(), NonZero::new(1).unwrap());
+        assert_eq!(reader.codec(), codec);
+        let expected = |i: u32| format!("ACGT{}", i).repeat(50).into_bytes();
+        assert!(
+            reader
+                .records()
+                .enumerate()
+                .all(|(i, data)| data == expected(i as u32))
+        );
+        assert_eq!(reader.get(1_200), Some(expected(1_200)));
+        assert_eq!(reader.get_by_key(b"77"), Some(expected(77)));
+
+        // stored payloads go to a file with the same codec without being decoded
+        let copied_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(copied_file.path(), NonZero::new(1).unwrap());
+        writer.set_codec(codec);
+        writer.start_write_worker();
+        for record in reader.gas_records().stored() {
+            assert!(record.stored && record.data.len() < expected(0).len());
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (copied, _recv) =
+            GasFileReader::new_reader(copied_file.path(), NonZero::new(1).unwrap());
+        assert_eq!(copied.get_stored(1_200), reader.get_stored(1_200));
+        assert_eq!(copied.get(1_200), Some(expected(1_200)));
+        assert_eq!(copied.get_by_key(b"77"), Some(expected(77)));
+    }
+
+    #[test]
+    fn test_gas_key_blooms() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(2).unwrap());
+        writer.start_write_worker();
+        for i in 0_u32..5_000 {
+            let record = GasRecord::new(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap())
+                .with_key(format!("read/{}", i));
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (reader, _recv) =
+            GasFileReader::new_reader(named_file.path(), NonZero::new(1).unwrap());
+        let decode =
+            |v: Vec<u8>| -> u32 { bincode::decode_from_slice(&v, get_bincode_cfg()).unwrap().0 };
+        assert!((0..5_000).all(|i| reader.may_contain_key(format!("read/{}", i).as_bytes())));
+        let false_positives = (5_000..10_000)
+            .filter(|i| reader.may_contain_key(format!("read/{}", i).as_bytes()))
+            .count();
+        assert!(
+            false_positives < 100,
+            "false_positives: {}",
+            false_positives
+        );
+
+        reader.retain_blocks_with_keys(&[b"read/1234", b"read/4321"]);
+        let records = reader.records().map(decode).collect::<Vec<_>>();
+        assert!(records.contains(&1234) && records.contains(&4321));
+        assert!(records.len() < 5_000);
+    }
+
+    #[test]
+    fn test_gas_zone_maps() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(1).unwrap());
+        writer.start_write_worker();
+        for i in 0_u32..5_000 {
+            let mut record = GasRecord::new(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap())
+                .with_stat("len", (i % 100) as f64);
+            // only the 3rd block has a high rq
+            if (2_000..3_000).contains(&i) {
+                record = record.with_stat("rq", 0.999);
+            } else if i % 2 == 0 {
+                record = record.with_stat("rq", 0.9);
+            }
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (reader, _recv) =
+            GasFileReader::new_reader(named_file.path(), NonZero::new(1).unwrap());
+        let decode =
+            |v: Vec<u8>| -> u32 { bincode::decode_from_slice(&v, get_bincode_cfg()).unwrap().0 };
+        let zone_maps = reader.zone_maps().unwrap();
+        assert_eq!(zone_maps.names, vec!["len".to_string(), "rq".to_string()]);
+        assert_eq!(zone_maps.blocks[0], vec![(0.0, 99.0), (0.9, 0.9)]);
+
+        let num_dropped = reader
+            .retain_blocks_matching(&["rq>=0.99".parse().unwrap(), "len<50".parse().unwrap()]);
+        assert_eq!(num_dropped, 4_000);
+        let records = reader.records().map(decode).collect::<Vec<_>>();
+        assert_eq!(records, (2_000..3_000).collect::<Vec<_>>());
+
+        assert_eq!(
+            reader.retain_blocks_matching(&["rq>1".parse().unwrap()]),
+            1_000
+        );
+        assert_eq!(reader.records().count(), 0);
+    }
+
+    #[test]
+    fn test_gas_shuffled_records() {
+        let named_file = NamedTempFile::new().unwrap();
+        let (writer, sender) =
+            GasFileWriter::new_writer(named_file.path(), NonZero::new(1).unwrap());
+        writer.start_write_worker();
+        for i in 0_u32..5_021 {
+            sender
+                .send(bincode::encode_to_vec(i, get_bincode_cfg()).unwrap().into())
+                .unwrap();
+        }
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (reader, _recv) =
+            GasFileReader::new_reader(named_file.path(), NonZero::new(1).unwrap());
+        let decode =
+            |v: Vec<u8>| -> u32 { bincode::decode_from_slice(&v, get_bincode_cfg()).unwrap().0 };
+
+        let in_order = reader.records().map(decode).collect::<Vec<_>>();
+        assert_eq!(in_order, (0_u32..5_021).collect::<Vec<_>>());
+
+        let epoch0 = reader
+            .shuffled_records(42, 0, 256)
+            .map(decode)
+            .collect::<Vec<_>>();
+        let epoch0_again = reader
+            .shuffled_records(42, 0, 256)
+            .map(decode)
+            .collect::<Vec<_>>();
+        let epoch1 = reader
+            .shuffled_records(42, 1, 256)
+            .map(decode)
+            .collect::<Vec<_>>();
+        assert_eq!(epoch0, epoch0_again);
+        assert_ne!(epoch0, epoch1);
+        assert_ne!(epoch0, in_order);
+
+        let mut sorted = epoch1.clone();
+        sorted.sort();
+        assert_eq!(sorted, in_order);
+        // pinned, a seed and epoch must keep giving the same order
+        assert_eq!(epoch0[..5], [17, 125, 68, 119, 204]);
+    }
 }
@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
+pub mod bloom;
+pub mod fastx;
+pub mod filter;
 pub mod io;
+pub mod reads;
+pub mod seq;
 
 pub trait TGasData: Serialize + DeserializeOwned + {
     fn obj_bytes(&self) -> usize {
@@ -0,0 +1,1352 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::c_char,
+    fmt::Display,
+    fs,
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+use crate::{
+    filter::StatFilter,
+    io::v1::{GasRecord, get_bincode_cfg},
+    seq::PackedSeq,
+};
+use gskits::gsbam::bam_record_ext::BamRecordExt;
+use rust_htslib::{
+    bam::{
+        Record,
+        record::{Aux, CigarString},
+    },
+    htslib,
+};
+use serde::Serialize;
+
+/// name of the section that holds the header text of the source bam, written by bam-gas-cvt b2g
+pub const BAM_HEADER_SECTION: &str = "bam.header";
+
+/// the header text of files whose reads are merged. the @HD/@SQ lines must be the same in all of them,
+/// @RG lines are joined by ID and @PG lines of later files get a `.N` suffix if their ID is taken
+pub fn merge_bam_headers(headers: &[&[u8]]) -> Vec<u8> {
+    let field = |line: &str, tag: &str| {
+        line.split('\t')
+            .find_map(|field| field.strip_prefix(tag)?.strip_prefix(':'))
+            .map(str::to_string)
+    };
+    let text = |header: &[u8]| String::from_utf8_lossy(header).into_owned();
+    let Some((first, rest)) = headers.split_first() else {
+        return vec![];
+    };
+    let first = text(first);
+    let layout = |text: &str| {
+        text.lines()
+            .filter(|line| line.starts_with("@HD") || line.starts_with("@SQ"))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let mut lines = first
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+
+    for (idx, header) in rest.iter().enumerate() {
+        let header = text(header);
+        assert_eq!(
+            layout(&header),
+            layout(&first),
+            "the @HD/@SQ lines of bam header {} differ from the first one, they can't be merged",
+            idx + 1
+        );
+        // renamed @PG IDs of this header, so its PP tags follow them
+        let mut pg_ids: HashMap<String, String> = HashMap::new();
+        for line in header.lines().filter(|line| !line.is_empty()) {
+            if line.starts_with("@HD") || line.starts_with("@SQ") {
+                continue;
+            }
+            if line.starts_with("@PG") {
+                let id = field(line, "ID").unwrap_or_default();
+                let renamed = |pg_ids: &HashMap<String, String>, new_id: &str| {
+                    line.split('\t')
+                        .map(|tag| match tag.split_once(':') {
+                            Some(("ID", _)) => format!("ID:{}", new_id),
+                            Some(("PP", pp)) => {
+                                format!("PP:{}", pg_ids.get(pp).map_or(pp, String::as_str))
+                            }
+                            _ => tag.to_string(),
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\t")
+                };
+                if lines.contains(&renamed(&pg_ids, &id)) {
+                    continue;
+                }
+                let taken = |id: &str| {
+                    lines.iter().any(|existing| {
+                        existing.starts_with("@PG") && field(existing, "ID").as_deref() == Some(id)
+                    })
+                };
+                let mut new_id = id.clone();
+                let mut suffix = 0;
+                while taken(&new_id) {
+                    suffix += 1;
+                    new_id = format!("{}.{}", id, suffix);
+                }
+                lines.push(renamed(&pg_ids, &new_id));
+                pg_ids.insert(id, new_id);
+                continue;
+            }
+            if line.starts_with("@RG") {
+                let id = field(line, "ID");
+                if let Some(existing) = lines
+                    .iter()
+                    .find(|existing| existing.starts_with("@RG") && field(existing, "ID") == id)
+                {
+                    assert_eq!(
+                        existing, line,
+                        "read group {:?} differs between the bam headers",
+                        id
+                    );
+                    continue;
+                }
+            }
+            if !lines.iter().any(|existing| existing == line) {
+                lines.push(line.to_string());
+            }
+        }
+    }
+    let mut merged = lines.join("\n").into_bytes();
+    merged.push(b'\n');
+    merged
+}
+
+/// name of the section that holds the TagSelection of bam-gas-cvt b2g, so consumers know which tags are present
+pub const TAG_SELECTION_SECTION: &str = "bam.tags";
+
+/// name of the section that holds the READS_FORMAT_VERSION the BatchReads of the file are encoded with
+pub const READS_FORMAT_SECTION: &str = "reads.format";
+
+/// bumped whenever the bincode layout of ReadInfo changes. 1: alignment, typed aux tags and 2-bit seq.
+/// 2: typed fields take any integer width, AuxValue::Typed keeps the width
+pub const READS_FORMAT_VERSION: u32 = 2;
+
+/// the READS_FORMAT_SECTION of the files written now
+pub fn reads_format_section() -> Vec<u8> {
+    READS_FORMAT_VERSION.to_le_bytes().to_vec()
+}
+
+/// panics unless the BatchReads of a file can be decoded by this build.
+/// `section` is the READS_FORMAT_SECTION of the file, files written before it existed have none
+pub fn check_reads_format(section: Option<&[u8]>) {
+    let section = section.unwrap_or_else(|| {
+        panic!(
+            "the file has no {} section, its reads are encoded by an older version. convert the source again",
+            READS_FORMAT_SECTION
+        )
+    });
+    let version = u32::from_le_bytes(section.try_into().unwrap_or_else(|_| {
+        panic!(
+            "invalid {} section. {} bytes",
+            READS_FORMAT_SECTION,
+            section.len()
+        )
+    }));
+    assert_eq!(
+        version, READS_FORMAT_VERSION,
+        "the reads are encoded in format {}, this version reads format {}. convert the source again",
+        version, READS_FORMAT_VERSION
+    );
+}
+
+/// one read of a bam/fasta/fastq file. this is the payload bam-gas-cvt writes, batched as BatchReads
+#[derive(Debug, Default, bincode::Encode, bincode::Decode, Serialize)]
+pub struct ReadInfo {
+    pub name: String,
+    pub seq: PackedSeq,
+    pub cx: Option<u8>,
+    pub ch: Option<u32>,
+    pub np: Option<u32>,
+    pub rq: Option<f32>,
+    pub qual: Option<Vec<u8>>, // phreq, no offset
+    pub dw: Option<Vec<u8>>,
+    pub ar: Option<Vec<u8>>,
+    pub cr: Option<Vec<u8>>,
+    pub be: Option<Vec<u32>>,
+    pub nn: Option<Vec<u8>>,
+    pub wd: Option<Vec<u8>>,    // linker width
+    pub sd: Option<Vec<u8>>,    // standard devition
+    pub sp: Option<Vec<u8>>,    // slope
+    pub aln: Option<Alignment>, // None for an unmapped read without mate info
+    pub tags: Vec<AuxTag>,      // aux tags that have no field above, e.g. RG, zm, fi/ri
+}
+
+/// tags that have a typed field in ReadInfo. one whose value does not fit the field goes to ReadInfo::tags
+pub const TYPED_TAGS: [&str; 12] = [
+    "cx", "ch", "np", "rq", "be", "dw", "ar", "cr", "nn", "wd", "sd", "sp",
+];
+
+/// the SAM type (the B subtype for arrays) of a typed field that needs no AuxValue::Typed
+fn default_type(tag: &str) -> u8 {
+    match tag {
+        "ch" | "np" | "be" => b'I',
+        "rq" => b'f',
+        _ => b'C',
+    }
+}
+
+/// the aux tags from_bam_record keeps. a dropped tag is never kept
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode, Serialize)]
+pub struct TagSelection {
+    pub all: bool, // keep every tag that is not dropped
+    pub keep: BTreeSet<String>,
+    pub drop: BTreeSet<String>,
+}
+
+impl TagSelection {
+    pub fn all() -> Self {
+        Self {
+            all: true,
+            keep: BTreeSet::new(),
+            drop: BTreeSet::new(),
+        }
+    }
+
+    pub fn none() -> Self {
+        Self {
+            all: false,
+            ..Self::all()
+        }
+    }
+
+    pub fn with_tags<I, T>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        for tag in tags {
+            let tag = tag.into();
+            self.drop.remove(&tag);
+            self.keep.insert(tag);
+        }
+        self
+    }
+
+    pub fn without_tags<I, T>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        for tag in tags {
+            let tag = tag.into();
+            self.keep.remove(&tag);
+            self.drop.insert(tag);
+        }
+        self
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        !self.drop.contains(tag) && (self.all || self.keep.contains(tag))
+    }
+
+    /// the tags either selection contains, e.g. for the reads of merged files
+    pub fn union(&self, other: &Self) -> Self {
+        let contained = |tag: &&String| self.contains(tag) || other.contains(tag);
+        if self.all || other.all {
+            let dropped = self.drop.union(&other.drop).filter(|tag| !contained(tag));
+            Self::all().without_tags(dropped.cloned().collect::<Vec<_>>())
+        } else {
+            let kept = self.keep.union(&other.keep).filter(contained);
+            Self::none().with_tags(kept.cloned().collect::<Vec<_>>())
+        }
+    }
+}
+
+impl Display for TagSelection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let join = |tags: &BTreeSet<String>| tags.iter().cloned().collect::<Vec<_>>().join(",");
+        match (self.all, self.drop.is_empty()) {
+            (true, true) => write!(f, "all"),
+            (true, false) => write!(f, "all except {}", join(&self.drop)),
+            (false, _) if self.keep.is_empty() => write!(f, "none"),
+            (false, _) => write!(f, "{}", join(&self.keep)),
+        }
+    }
+}
+
+/// an aux tag of a bam record with its exact type
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode, Serialize)]
+pub struct AuxTag {
+    pub tag: String,
+    pub value: AuxValue,
+}
+
+/// owned rust_htslib Aux
+#[derive(Debug, Clone, PartialEq, bincode::Encode, bincode::Decode, Serialize)]
+pub enum AuxValue {
+    Char(u8),
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    Float(f32),
+    Double(f64),
+    String(String),
+    HexByteArray(String),
+    ArrayI8(Vec<i8>),
+    ArrayU8(Vec<u8>),
+    ArrayI16(Vec<i16>),
+    ArrayU16(Vec<u16>),
+    ArrayI32(Vec<i32>),
+    ArrayU32(Vec<u32>),
+    ArrayFloat(Vec<f32>),
+    /// not a value: the typed field of ReadInfo with this tag holds it, and the bam stores it as
+    /// this SAM type (c/C/s/S/i/I/f/d, the B subtype for arrays). only kept if it is not the default type of the field
+    Typed(u8),
+}
+
+impl From<Aux<'_>> for AuxValue {
+    fn from(aux: Aux<'_>) -> Self {
+        match aux {
+            Aux::Char(v) => Self::Char(v),
+            Aux::I8(v) => Self::I8(v),
+            Aux::U8(v) => Self::U8(v),
+            Aux::I16(v) => Self::I16(v),
+            Aux::U16(v) => Self::U16(v),
+            Aux::I32(v) => Self::I32(v),
+            Aux::U32(v) => Self::U32(v),
+            Aux::Float(v) => Self::Float(v),
+            Aux::Double(v) => Self::Double(v),
+            Aux::String(v) => Self::String(v.to_string()),
+            Aux::HexByteArray(v) => Self::HexByteArray(v.to_string()),
+            Aux::ArrayI8(v) => Self::ArrayI8(v.iter().collect()),
+            Aux::ArrayU8(v) => Self::ArrayU8(v.iter().collect()),
+            Aux::ArrayI16(v) => Self::ArrayI16(v.iter().collect()),
+            Aux::ArrayU16(v) => Self::ArrayU16(v.iter().collect()),
+            Aux::ArrayI32(v) => Self::ArrayI32(v.iter().collect()),
+            Aux::ArrayU32(v) => Self::ArrayU32(v.iter().collect()),
+            Aux::ArrayFloat(v) => Self::ArrayFloat(v.iter().collect()),
+        }
+    }
+}
+
+/// the integer of a scalar aux with its SAM type
+fn aux_int(aux: &Aux<'_>) -> Option<(i64, u8)> {
+    match *aux {
+        Aux::I8(v) => Some((v as i64, b'c')),
+        Aux::U8(v) => Some((v as i64, b'C')),
+        Aux::I16(v) => Some((v as i64, b's')),
+        Aux::U16(v) => Some((v as i64, b'S')),
+        Aux::I32(v) => Some((v as i64, b'i')),
+        Aux::U32(v) => Some((v as i64, b'I')),
+        _ => None,
+    }
+}
+
+/// the integers of an integer array aux with its B subtype
+fn aux_ints(aux: &Aux<'_>) -> Option<(Vec<i64>, u8)> {
+    match aux {
+        Aux::ArrayI8(v) => Some((v.iter().map(i64::from).collect(), b'c')),
+        Aux::ArrayU8(v) => Some((v.iter().map(i64::from).collect(), b'C')),
+        Aux::ArrayI16(v) => Some((v.iter().map(i64::from).collect(), b's')),
+        Aux::ArrayU16(v) => Some((v.iter().map(i64::from).collect(), b'S')),
+        Aux::ArrayI32(v) => Some((v.iter().map(i64::from).collect(), b'i')),
+        Aux::ArrayU32(v) => Some((v.iter().map(i64::from).collect(), b'I')),
+        _ => None,
+    }
+}
+
+/// a value of a typed field as the SAM type it came with. the value fits, from_bam_record checked it
+fn int_aux(v: i64, typ: u8) -> AuxValue {
+    match typ {
+        b'c' => AuxValue::I8(v as i8),
+        b'C' => AuxValue::U8(v as u8),
+        b's' => AuxValue::I16(v as i16),
+        b'S' => AuxValue::U16(v as u16),
+        b'i' => AuxValue::I32(v as i32),
+        _ => AuxValue::U32(v as u32),
+    }
+}
+
+/// see int_aux
+fn int_array_aux<T: Copy + Into<i64>>(values: &[T], typ: u8) -> AuxValue {
+    let values = values.iter().map(|&v| v.into());
+    match typ {
+        b'c' => AuxValue::ArrayI8(values.map(|v| v as i8).collect()),
+        b'C' => AuxValue::ArrayU8(values.map(|v| v as u8).collect()),
+        b's' => AuxValue::ArrayI16(values.map(|v| v as i16).collect()),
+        b'S' => AuxValue::ArrayU16(values.map(|v| v as u16).collect()),
+        b'i' => AuxValue::ArrayI32(values.map(|v| v as i32).collect()),
+        _ => AuxValue::ArrayU32(values.map(|v| v as u32).collect()),
+    }
+}
+
+impl AuxValue {
+    /// rust_htslib reads hex arrays as strings, the type byte of the tag tells them apart
+    fn from_bam_aux(record: &Record, tag: &[u8], aux: Aux<'_>) -> Self {
+        match aux {
+            Aux::String(v) => {
+                let c_tag = [tag[0] as c_char, tag[1] as c_char, 0];
+                let typ = unsafe { *htslib::bam_aux_get(record.inner(), c_tag.as_ptr()) };
+                if typ as u8 == b'H' {
+                    Self::HexByteArray(v.to_string())
+                } else {
+                    Self::String(v.to_string())
+                }
+            }
+            aux => aux.into(),
+        }
+    }
+
+    /// the value of a numeric scalar
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::I8(v) => Some(v as f64),
+            Self::U8(v) => Some(v as f64),
+            Self::I16(v) => Some(v as f64),
+            Self::U16(v) => Some(v as f64),
+            Self::I32(v) => Some(v as f64),
+            Self::U32(v) => Some(v as f64),
+            Self::Float(v) => Some(v as f64),
+            Self::Double(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn to_aux(&self) -> Aux<'_> {
+        match self {
+            Self::Char(v) => Aux::Char(*v),
+            Self::I8(v) => Aux::I8(*v),
+            Self::U8(v) => Aux::U8(*v),
+            Self::I16(v) => Aux::I16(*v),
+            Self::U16(v) => Aux::U16(*v),
+            Self::I32(v) => Aux::I32(*v),
+            Self::U32(v) => Aux::U32(*v),
+            Self::Float(v) => Aux::Float(*v),
+            Self::Double(v) => Aux::Double(*v),
+            Self::String(v) => Aux::String(v),
+            Self::HexByteArray(v) => Aux::HexByteArray(v),
+            Self::ArrayI8(v) => Aux::ArrayI8(v.into()),
+            Self::ArrayU8(v) => Aux::ArrayU8(v.into()),
+            Self::ArrayI16(v) => Aux::ArrayI16(v.into()),
+            Self::ArrayU16(v) => Aux::ArrayU16(v.into()),
+            Self::ArrayI32(v) => Aux::ArrayI32(v.into()),
+            Self::ArrayU32(v) => Aux::ArrayU32(v.into()),
+            Self::ArrayFloat(v) => Aux::ArrayFloat(v.into()),
+            Self::Typed(_) => panic!("AuxValue::Typed is not a value"),
+        }
+    }
+}
+
+/// the alignment fields of a bam record, kept as they are so aligned bam -> gas -> bam is lossless
+#[derive(Debug, Clone, PartialEq, Eq, bincode::Encode, bincode::Decode, Serialize)]
+pub struct Alignment {
+    pub tid: i32,
+    pub pos: i64,
+    pub bin: u16,
+    pub flag: u16,
+    pub mapq: u8,
+    pub cigar: String, // e.g. 10M2I5M, empty if there is none
+    pub mtid: i32,
+    pub mpos: i64,
+    pub isize: i64,
+}
+
+impl Alignment {
+    /// bin of a read without a position, reg2bin(-1, 0)
+    pub const UNMAPPED_BIN: u16 = 4680;
+
+    /// None if the record is an unmapped read without a position or mate, to_record restores it from
+    /// unmapped(). the mapq of such a read is meaningless, it comes back as 255 (unavailable)
+    pub fn from_bam_record(record: &Record) -> Option<Self> {
+        if record.is_unmapped()
+            && record.flags() == 4
+            && record.tid() < 0
+            && record.pos() < 0
+            && record.mtid() < 0
+            && record.mpos() < 0
+        {
+            return None;
+        }
+        Some(Self {
+            tid: record.tid(),
+            pos: record.pos(),
+            bin: record.bin(),
+            flag: record.flags(),
+            mapq: record.mapq(),
+            cigar: record.cigar().to_string(),
+            mtid: record.mtid(),
+            mpos: record.mpos(),
+            isize: record.insert_size(),
+        })
+    }
+
+    pub fn unmapped() -> Self {
+        Self {
+            tid: -1,
+            pos: -1,
+            bin: Self::UNMAPPED_BIN,
+            flag: 4,
+            mapq: 255,
+            cigar: String::new(),
+            mtid: -1,
+            mpos: -1,
+            isize: 0,
+        }
+    }
+
+    fn cigar(&self) -> Option<CigarString> {
+        (!self.cigar.is_empty()).then(|| {
+            CigarString::try_from(self.cigar.as_str())
+                .unwrap_or_else(|err| panic!("invalid cigar {}. {}", self.cigar, err))
+        })
+    }
+}
+
+impl ReadInfo {
+    pub fn new_fa_record(name: String, seq: String) -> Self {
+        Self {
+            name,
+            seq: seq.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_fq_record(name: String, seq: String, qual: Vec<u8>) -> Self {
+        let mut res = ReadInfo::new_fa_record(name, seq);
+        res.qual = Some(qual);
+        res
+    }
+
+    pub fn from_bam_record(
+        record: &Record,
+        qname_suffix: Option<&str>,
+        tags: &TagSelection,
+    ) -> Self {
+        let mut qname = unsafe { String::from_utf8_unchecked(record.qname().to_vec()) };
+        if let Some(suffix) = qname_suffix {
+            qname.push_str(suffix);
+        }
+        let record_ext = BamRecordExt::new(record);
+        // bam bases are always one of =ACMGRSVTWYHKDBN
+        let seq = PackedSeq(String::from_utf8(record.seq().as_bytes()).unwrap());
+
+        let mut read = Self {
+            name: qname,
+            seq,
+            qual: Some(record_ext.get_qual().to_vec()),
+            aln: Alignment::from_bam_record(record),
+            ..Default::default()
+        };
+        for (tag, aux) in record.aux_iter().map(|aux| aux.unwrap()) {
+            let name = String::from_utf8_lossy(tag);
+            if !tags.contains(&name) {
+                continue;
+            }
+            // a typed field takes the tag if the value fits, whatever the width. the SAM type goes to tags
+            // as AuxValue::Typed unless it is the default one, so to_record restores the tag as it was
+            macro_rules! int_field {
+                ($field:ident, $ty:ty) => {
+                    aux_int(&aux).and_then(|(v, typ)| {
+                        read.$field = Some(<$ty>::try_from(v).ok()?);
+                        Some(typ)
+                    })
+                };
+            }
+            macro_rules! array_field {
+                ($field:ident, $ty:ty) => {
+                    aux_ints(&aux).and_then(|(values, typ)| {
+                        let values = values.into_iter().map(<$ty>::try_from);
+                        read.$field = Some(values.collect::<Result<_, _>>().ok()?);
+                        Some(typ)
+                    })
+                };
+            }
+            let typed = match name.as_ref() {
+                "cx" => int_field!(cx, u8),
+                "ch" => int_field!(ch, u32),
+                "np" => int_field!(np, u32),
+                "rq" => match aux {
+                    Aux::Float(v) => {
+                        read.rq = Some(v);
+                        Some(b'f')
+                    }
+                    Aux::Double(v) if v as f32 as f64 == v => {
+                        read.rq = Some(v as f32);
+                        Some(b'd')
+                    }
+                    _ => None,
+                },
+                "be" => array_field!(be, u32),
+                "dw" => array_field!(dw, u8),
+                "ar" => array_field!(ar, u8),
+                "cr" => array_field!(cr, u8),
+                "nn" => array_field!(nn, u8),
+                "wd" => array_field!(wd, u8),
+                "sd" => array_field!(sd, u8),
+                "sp" => array_field!(sp, u8),
+                _ => None,
+            };
+            let value = match typed {
+                Some(typ) if typ == default_type(&name) => continue,
+                Some(typ) => AuxValue::Typed(typ),
+                None => AuxValue::from_bam_aux(record, tag, aux),
+            };
+            read.tags.push(AuxTag {
+                tag: name.into_owned(),
+                value,
+            });
+        }
+        read
+    }
+
+    /// the SAM type a typed field is written with, see AuxValue::Typed
+    fn typed_field_type(&self, tag: &str) -> u8 {
+        self.tags
+            .iter()
+            .find_map(|aux| match aux.value {
+                AuxValue::Typed(typ) if aux.tag == tag => Some(typ),
+                _ => None,
+            })
+            .unwrap_or_else(|| default_type(tag))
+    }
+
+    /// a numeric tag kept in ReadInfo::tags, e.g. a ch stored as i32 or a rq stored as double
+    fn tag_number(&self, tag: &str) -> Option<f64> {
+        self.tags
+            .iter()
+            .find(|aux| aux.tag == tag)
+            .and_then(|aux| aux.value.as_f64())
+    }
+
+    /// a numeric field of the read, see READ_STATS. None if the read does not have it
+    pub fn stat(&self, name: &str) -> Option<f64> {
+        match name {
+            "len" => Some(self.seq.len() as f64),
+            "rq" => self
+                .rq
+                .map(|rq| rq as f64)
+                .or_else(|| self.tag_number("rq")),
+            "np" => self
+                .np
+                .map(|np| np as f64)
+                .or_else(|| self.tag_number("np")),
+            "ch" => self
+                .ch
+                .map(|ch| ch as f64)
+                .or_else(|| self.tag_number("ch")),
+            "cx" => self
+                .cx
+                .map(|cx| cx as f64)
+                .or_else(|| self.tag_number("cx")),
+            "mapq" => self.aln.as_ref().map(|aln| aln.mapq as f64),
+            name => panic!(
+                "invalid stat. {}. only {} are valid",
+                name,
+                READ_STATS.join("/")
+            ),
+        }
+    }
+
+    /// the key a read is looked up by. name/ch
+    pub fn key(&self, key: &str) -> Option<Vec<u8>> {
+        match key {
+            "name" => Some(self.name.as_bytes().to_vec()),
+            "ch" => self
+                .stat("ch")
+                .map(|ch| (ch as i64).to_string().into_bytes()),
+            key => panic!("invalid key. {}. only name/ch are valid", key),
+        }
+    }
+
+    pub fn to_record(&self) -> Record {
+        let mut record = Record::new();
+        let aln = self.aln.clone().unwrap_or_else(Alignment::unmapped);
+
+        // 设置 qname
+
+        // 设置 seq
+        // 0xff marks missing qualities, e.g. reads from fasta. only allocated then
+        let missing_qual;
+        let qual = match &self.qual {
+            Some(qual) => qual.as_slice(),
+            None => {
+                missing_qual = vec![0xff; self.seq.len()];
+                &missing_qual
+            }
+        };
+        record.set(
+            self.name.as_bytes(),
+            aln.cigar().as_ref(),
+            self.seq.as_bytes(),
+            qual,
+        );
+        record.set_tid(aln.tid);
+        record.set_pos(aln.pos);
+        record.set_bin(aln.bin);
+        record.set_flags(aln.flag);
+        record.set_mapq(aln.mapq);
+        record.set_mtid(aln.mtid);
+        record.set_mpos(aln.mpos);
+        record.set_insert_size(aln.isize);
+        // 设置 tags
+        macro_rules! push_aux {
+            ($tag:expr, $value:expr) => {
+                record.push_aux($tag, $value).unwrap();
+            };
+        }
+
+        for (tag, v) in [
+            ("cx", self.cx.map(u32::from)),
+            ("ch", self.ch),
+            ("np", self.np),
+        ] {
+            if let Some(v) = v {
+                let value = int_aux(v as i64, self.typed_field_type(tag));
+                push_aux!(tag.as_bytes(), value.to_aux());
+            }
+        }
+        if let Some(rq) = self.rq {
+            let value = match self.typed_field_type("rq") {
+                b'd' => Aux::Double(rq as f64),
+                _ => Aux::Float(rq),
+            };
+            push_aux!(b"rq", value);
+        }
+        if let Some(be) = &self.be {
+            let value = int_array_aux(be, self.typed_field_type("be"));
+            push_aux!(b"be", value.to_aux());
+        }
+        for (tag, values) in [
+            ("dw", &self.dw),
+            ("ar", &self.ar),
+            ("cr", &self.cr),
+            ("nn", &self.nn),
+            ("wd", &self.wd),
+            ("sd", &self.sd),
+            ("sp", &self.sp),
+        ] {
+            if let Some(values) = values {
+                let value = int_array_aux(values, self.typed_field_type(tag));
+                push_aux!(tag.as_bytes(), value.to_aux());
+            }
+        }
+        for tag in &self.tags {
+            if !matches!(tag.value, AuxValue::Typed(_)) {
+                push_aux!(tag.tag.as_bytes(), tag.value.to_aux());
+            }
+        }
+
+        record
+    }
+}
+
+/// the numeric fields ReadInfo::stat supports
+pub const READ_STATS: [&str; 6] = ["len", "rq", "np", "ch", "cx", "mapq"];
+
+/// keeps the reads that match all the stat filters, are in the include list (if any)
+/// and are not in the exclude list. a read without the field of a stat filter is dropped
+#[derive(Debug, Clone, Default)]
+pub struct ReadFilter {
+    pub stats: Vec<StatFilter>,
+    pub include: Option<HashSet<String>>,
+    pub exclude: HashSet<String>,
+}
+
+impl ReadFilter {
+    /// panics if a stat filter is not on one of READ_STATS
+    pub fn new(
+        stats: Vec<StatFilter>,
+        include: Option<HashSet<String>>,
+        exclude: HashSet<String>,
+    ) -> Self {
+        for filter in &stats {
+            assert!(
+                READ_STATS.contains(&filter.name.as_str()),
+                "invalid filter: {}. only {} can be filtered",
+                filter,
+                READ_STATS.join("/")
+            );
+        }
+        Self {
+            stats,
+            include,
+            exclude,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stats.is_empty() && self.include.is_none() && self.exclude.is_empty()
+    }
+
+    pub fn keep(&self, read: &ReadInfo) -> bool {
+        self.stats.iter().all(|filter| {
+            read.stat(&filter.name)
+                .is_some_and(|value| filter.matches(value))
+        }) && self
+            .include
+            .as_ref()
+            .is_none_or(|names| names.contains(&read.name))
+            && !self.exclude.contains(&read.name)
+    }
+}
+
+/// read names, one per line. anything after the first whitespace is ignored, so are empty lines
+pub fn read_name_list<P: AsRef<Path>>(path: P) -> HashSet<String> {
+    fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("read {:?} error. {}", path.as_ref(), err))
+        .lines()
+        .filter_map(|line| line.split_ascii_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+/// a hole of a movie, the subreads of a zmw share it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ZmwId {
+    pub movie: String,
+    pub hole: u32,
+}
+
+impl ZmwId {
+    /// the movie is the read name up to the first '/'. the hole is the ch/zm tag,
+    /// or the zmw field of a `movie/zmw/start_end` name
+    pub fn from_bam_record(record: &Record) -> Option<Self> {
+        let record_ext = BamRecordExt::new(record);
+        let name = record_ext.get_qname();
+        let mut fields = name.split('/');
+        let movie = fields.next()?.to_string();
+        let hole = record_ext
+            .get_ch()
+            .or_else(|| record_ext.get_int(b"zm"))
+            .or_else(|| fields.next()?.parse().ok())?;
+        Some(Self { movie, hole })
+    }
+}
+
+/// groups the consecutive records of a zmw, whatever the group size.
+/// panics unless the records are in subread bam order: the records of a zmw are
+/// consecutive and the holes of a movie are ascending
+pub struct ZmwGroups<I: Iterator<Item = Record>> {
+    records: I,
+    pending: Option<(ZmwId, Record)>,
+    last_holes: HashMap<String, u32>,
+}
+
+impl<I: Iterator<Item = Record>> ZmwGroups<I> {
+    pub fn new(records: I) -> Self {
+        Self {
+            records,
+            pending: None,
+            last_holes: HashMap::new(),
+        }
+    }
+
+    fn next_with_zmw(&mut self) -> Option<(ZmwId, Record)> {
+        let record = self.records.next()?;
+        let zmw = ZmwId::from_bam_record(&record).unwrap_or_else(|| {
+            panic!(
+                "can't find the zmw of read {}. expect the ch/zm tag or a movie/zmw/start_end name",
+                String::from_utf8_lossy(record.qname())
+            )
+        });
+        Some((zmw, record))
+    }
+}
+
+impl<I: Iterator<Item = Record>> Iterator for ZmwGroups<I> {
+    type Item = Vec<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (zmw, first) = self.pending.take().or_else(|| self.next_with_zmw())?;
+        if let Some(last_hole) = self.last_holes.insert(zmw.movie.clone(), zmw.hole) {
+            assert!(
+                zmw.hole > last_hole,
+                "zmw {}/{} after {}/{}. group by zmw needs a subread bam order",
+                zmw.movie,
+                zmw.hole,
+                zmw.movie,
+                last_hole
+            );
+        }
+        let mut group = vec![first];
+        while let Some((next_zmw, record)) = self.next_with_zmw() {
+            if next_zmw != zmw {
+                self.pending = Some((next_zmw, record));
+                break;
+            }
+            group.push(record);
+        }
+        Some(group)
+    }
+}
+
+#[derive(Debug, bincode::Encode, bincode::Decode, Serialize)]
+pub struct BatchReads(pub Vec<ReadInfo>);
+impl Deref for BatchReads {
+    type Target = Vec<ReadInfo>;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for BatchReads {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl BatchReads {
+    /// decode an encoded batch one read at a time, each read comes with the bytes it is encoded in
+    pub fn decode_iter(data: &[u8]) -> impl Iterator<Item = (ReadInfo, &[u8])> + '_ {
+        let cfg = get_bincode_cfg();
+        let (num_reads, mut offset): (usize, usize) =
+            bincode::decode_from_slice(data, cfg).unwrap();
+        (0..num_reads).map(move |_| {
+            let (read, nbytes): (ReadInfo, usize) =
+                bincode::decode_from_slice(&data[offset..], cfg).unwrap();
+            offset += nbytes;
+            (read, &data[offset - nbytes..offset])
+        })
+    }
+
+    /// encode the batch as a gas record, with the `key` (name/ch) of every read
+    /// and the len/rq/np statistics for zone maps, so that readers can skip blocks by them
+    pub fn to_gas_record(&self, key: Option<&str>) -> GasRecord {
+        let serial = bincode::encode_to_vec(self, get_bincode_cfg()).unwrap();
+        let mut gas_record = GasRecord::new(serial);
+        for read in self.iter() {
+            gas_record = gas_record.with_stat("len", read.seq.len() as f64);
+            if let Some(rq) = read.stat("rq") {
+                gas_record = gas_record.with_stat("rq", rq);
+            }
+            if let Some(np) = read.stat("np") {
+                gas_record = gas_record.with_stat("np", np);
+            }
+        }
+        if let Some(key) = key {
+            let mut keys = self
+                .iter()
+                .filter_map(|read| read.key(key))
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys.dedup();
+            gas_record.keys = keys;
+        }
+        gas_record
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, num::NonZero, sync::Arc};
+
+    use rust_htslib::bam::{
+        self, Read, Record,
+        record::{Aux, CigarString},
+    };
+    use tempfile::NamedTempFile;
+
+    use crate::io::{
+        storage::SeekStorage,
+        v1::{GasFileReader, GasFileWriter, get_bincode_cfg},
+    };
+
+    use super::{
+        Alignment, AuxValue, BAM_HEADER_SECTION, BatchReads, READS_FORMAT_VERSION, ReadFilter,
+        ReadInfo, TagSelection, ZmwGroups, ZmwId, check_reads_format, merge_bam_headers,
+        reads_format_section,
+    };
+
+    fn core(record: &Record) -> (i32, i64, u16, u16, u8, i32, i64, i64, Vec<u32>) {
+        (
+            record.tid(),
+            record.pos(),
+            record.bin(),
+            record.flags(),
+            record.mapq(),
+            record.mtid(),
+            record.mpos(),
+            record.insert_size(),
+            record.raw_cigar().to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_aligned_read_round_trip() {
+        let mut record = Record::new();
+        let cigar = CigarString::try_from("2S5M1I2M").unwrap();
+        record.set(b"read/1", Some(&cigar), b"ACGTACGTAC", &[30; 10]);
+        record.set_tid(1);
+        record.set_pos(100);
+        record.set_bin(4681);
+        record.set_flags(0x1 | 0x2 | 0x10 | 0x40);
+        record.set_mapq(60);
+        record.set_mtid(1);
+        record.set_mpos(300);
+        record.set_insert_size(210);
+
+        let read = ReadInfo::from_bam_record(&record, None, &TagSelection::all());
+        assert_eq!(read.aln.as_ref().unwrap().cigar, "2S5M1I2M");
+        let restored = read.to_record();
+        assert_eq!(core(&restored), core(&record));
+        assert_eq!(restored.qname(), record.qname());
+
+        let mut tagged = Record::new();
+        tagged.set(b"read/3", None, b"ACGT", &[30; 4]);
+        tagged.push_aux(b"RG", Aux::String("rg1")).unwrap();
+        tagged.push_aux(b"zm", Aux::I32(-7)).unwrap();
+        tagged
+            .push_aux(b"sn", Aux::ArrayFloat((&vec![1.5_f32, 2.0]).into()))
+            .unwrap();
+        tagged
+            .push_aux(b"fi", Aux::ArrayU16((&vec![1_u16, 300]).into()))
+            .unwrap();
+        tagged.push_aux(b"ch", Aux::U32(7)).unwrap();
+        tagged
+            .push_aux(b"dw", Aux::ArrayU8((&vec![1_u8, 2]).into()))
+            .unwrap();
+        let read = ReadInfo::from_bam_record(
+            &tagged,
+            None,
+            &TagSelection::all().without_tags(["dw", "xx"]),
+        );
+        assert_eq!(read.ch, Some(7));
+        assert_eq!(read.dw, None);
+        assert_eq!(
+            read.tags
+                .iter()
+                .map(|tag| tag.tag.as_str())
+                .collect::<Vec<_>>(),
+            vec!["RG", "zm", "sn", "fi"]
+        );
+        assert_eq!(read.tags[1].value, AuxValue::I32(-7));
+        let restored = read.to_record();
+        for tag in [b"RG", b"zm", b"sn", b"fi", b"ch"] {
+            assert_eq!(restored.aux(tag).unwrap(), tagged.aux(tag).unwrap());
+        }
+        assert!(restored.aux(b"dw").is_err());
+
+        let read =
+            ReadInfo::from_bam_record(&tagged, None, &TagSelection::none().with_tags(["zm", "dw"]));
+        assert_eq!(read.ch, None);
+        assert_eq!(read.dw, Some(vec![1, 2]));
+        assert_eq!(read.tags.len(), 1);
+        assert_eq!(read.tags[0].tag, "zm");
+
+        let mut unmapped = Record::new();
+        unmapped.set(b"read/2", None, b"ACGT", &[30; 4]);
+        unmapped.set_tid(-1);
+        unmapped.set_pos(-1);
+        unmapped.set_bin(Alignment::UNMAPPED_BIN);
+        unmapped.set_flags(4);
+        unmapped.set_mtid(-1);
+        unmapped.set_mpos(-1);
+        unmapped.set_mapq(255);
+        let read = ReadInfo::from_bam_record(&unmapped, None, &TagSelection::all());
+        assert_eq!(read.aln, None);
+        assert_eq!(core(&read.to_record()), core(&unmapped));
+
+        // whatever the mapq, an unmapped read without position stores no alignment
+        unmapped.set_mapq(0);
+        let read = ReadInfo::from_bam_record(&unmapped, None, &TagSelection::all());
+        assert_eq!(read.aln, None);
+    }
+
+    #[test]
+    fn test_read_filter() {
+        let mut read = ReadInfo::new_fa_record("read/1".to_string(), "ACGT".repeat(300));
+        read.rq = Some(0.995);
+        read.np = Some(2);
+
+        let filter = ReadFilter::new(
+            vec!["rq>=0.99".parse().unwrap(), "len>=1000".parse().unwrap()],
+            None,
+            Default::default(),
+        );
+        assert!(filter.keep(&read));
+        let filter = ReadFilter::new(vec!["np>=3".parse().unwrap()], None, Default::default());
+        assert!(!filter.keep(&read));
+        // reads without the field are dropped
+        let filter = ReadFilter::new(vec!["ch>=0".parse().unwrap()], None, Default::default());
+        assert!(!filter.keep(&read));
+
+        let filter = ReadFilter::new(
+            vec![],
+            Some(["read/1".to_string()].into()),
+            ["read/2".to_string()].into(),
+        );
+        assert!(filter.keep(&read));
+        read.name = "read/2".to_string();
+        assert!(!filter.keep(&read));
+
+        assert!(
+            std::panic::catch_unwind(|| {
+                ReadFilter::new(vec!["qv>=20".parse().unwrap()], None, Default::default())
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_typed_tags_keep_their_type() {
+        let mut record = Record::new();
+        record.set(b"read/4", None, b"ACGT", &[30; 4]);
+        record
+            .push_aux(b"cr", Aux::ArrayFloat((&vec![0.5_f32, 1.5]).into()))
+            .unwrap();
+        record
+            .push_aux(b"dw", Aux::ArrayU16((&vec![3_u16, 700]).into()))
+            .unwrap();
+        record
+            .push_aux(b"ar", Aux::ArrayU16((&vec![3_u16, 200]).into()))
+            .unwrap();
+        record.push_aux(b"rq", Aux::Double(0.999)).unwrap();
+        record.push_aux(b"ch", Aux::I32(12)).unwrap();
+        record.push_aux(b"np", Aux::U8(7)).unwrap();
+        record.push_aux(b"cx", Aux::I16(-1)).unwrap();
+        record
+            .push_aux(b"sd", Aux::ArrayU8((&vec![1_u8]).into()))
+            .unwrap();
+        record
+            .push_aux(b"sp", Aux::ArrayU8((&vec![2_u8]).into()))
+            .unwrap();
+
+        // any integer width that fits fills the typed field, the rest stays in tags
+        let read = ReadInfo::from_bam_record(&record, None, &TagSelection::all());
+        assert_eq!((read.ch, read.np, read.cx), (Some(12), Some(7), None));
+        assert_eq!(read.ar, Some(vec![3, 200]));
+        assert_eq!(
+            (read.cr.as_ref(), read.dw.as_ref(), read.rq),
+            (None, None, None)
+        );
+        assert_eq!(
+            (read.sd.clone(), read.sp.clone()),
+            (Some(vec![1]), Some(vec![2]))
+        );
+        assert_eq!(read.stat("rq"), Some(0.999));
+        assert_eq!(read.stat("cx"), Some(-1.0));
+        assert_eq!(read.key("ch"), Some(b"12".to_vec()));
+
+        let restored = read.to_record();
+        for tag in [
+            b"cr", b"dw", b"ar", b"rq", b"ch", b"np", b"cx", b"sd", b"sp",
+        ] {
+            assert_eq!(restored.aux(tag).unwrap(), record.aux(tag).unwrap());
+        }
+        let mut rq_float = Record::new();
+        rq_float.push_aux(b"rq", Aux::Double(0.5)).unwrap();
+        let read = ReadInfo::from_bam_record(&rq_float, None, &TagSelection::all());
+        assert_eq!(read.rq, Some(0.5));
+        assert_eq!(read.to_record().aux(b"rq").unwrap(), Aux::Double(0.5));
+    }
+
+    #[test]
+    fn test_reads_format() {
+        check_reads_format(Some(&reads_format_section()));
+        let older = (READS_FORMAT_VERSION - 1).to_le_bytes();
+        assert!(std::panic::catch_unwind(|| check_reads_format(Some(&older))).is_err());
+        assert!(std::panic::catch_unwind(|| check_reads_format(None)).is_err());
+    }
+
+    fn subread(name: &str) -> Record {
+        let mut record = Record::new();
+        record.set(name.as_bytes(), None, b"ACGT", &[20; 4]);
+        record
+    }
+
+    #[test]
+    fn test_zmw_groups() {
+        let mut tagged = subread("m1/5/ccs");
+        tagged.push_aux(b"zm", Aux::I32(9)).unwrap();
+        assert_eq!(
+            ZmwId::from_bam_record(&tagged),
+            Some(ZmwId {
+                movie: "m1".to_string(),
+                hole: 9
+            })
+        );
+        assert_eq!(ZmwId::from_bam_record(&subread("read1")), None);
+
+        let records = [
+            "m1/1/0_10",
+            "m1/1/12_20",
+            "m1/3/0_5",
+            "m2/1/0_8",
+            "m2/1/9_30",
+        ];
+        let groups = ZmwGroups::new(records.into_iter().map(subread))
+            .map(|group| group.len())
+            .collect::<Vec<_>>();
+        assert_eq!(groups, vec![2, 1, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "subread bam order")]
+    fn test_zmw_groups_unordered() {
+        let records = ["m1/1/0_10", "m1/3/0_5", "m1/1/12_20"];
+        ZmwGroups::new(records.into_iter().map(subread)).for_each(drop);
+    }
+
+    #[test]
+    fn test_merge_bam_headers() {
+        let first = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n@RG\tID:a\tSM:x\n@PG\tID:ccs\tPN:ccs\n";
+        let second = "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n@RG\tID:a\tSM:x\n@RG\tID:b\tSM:y\n\
+            @PG\tID:ccs\tPN:ccs\tVN:2\n@PG\tID:bam2gas\tPN:gas\tPP:ccs\n@PG\tID:ccs\tPN:ccs\n";
+        let merged = merge_bam_headers(&[first.as_bytes(), second.as_bytes()]);
+        assert_eq!(
+            String::from_utf8(merged).unwrap(),
+            "@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n@RG\tID:a\tSM:x\n@PG\tID:ccs\tPN:ccs\n\
+            @RG\tID:b\tSM:y\n@PG\tID:ccs.1\tPN:ccs\tVN:2\n@PG\tID:bam2gas\tPN:gas\tPP:ccs.1\n"
+        );
+        assert_eq!(merge_bam_headers(&[first.as_bytes()]), first.as_bytes());
+    }
+
+    #[test]
+    #[should_panic(expected = "@HD/@SQ lines")]
+    fn test_merge_bam_headers_other_references() {
+        merge_bam_headers(&[
+            b"@SQ\tSN:chr1\tLN:100\n".as_slice(),
+            b"@SQ\tSN:chr2\tLN:100\n".as_slice(),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "read group")]
+    fn test_merge_bam_headers_other_read_group() {
+        merge_bam_headers(&[
+            b"@RG\tID:a\tSM:x\n".as_slice(),
+            b"@RG\tID:a\tSM:y\n".as_slice(),
+        ]);
+    }
+
+    #[test]
+    fn test_tag_selection_union() {
+        let some = TagSelection::none().with_tags(["zm", "np"]);
+        let others = TagSelection::none().with_tags(["np", "rq"]);
+        let union = some.union(&others);
+        assert_eq!(union, TagSelection::none().with_tags(["zm", "np", "rq"]));
+
+        let most = TagSelection::all().without_tags(["dw", "fi"]);
+        let union = most.union(&TagSelection::none().with_tags(["dw"]));
+        assert_eq!(union, TagSelection::all().without_tags(["fi"]));
+        assert!(union.contains("dw") && union.contains("zm") && !union.contains("fi"));
+    }
+
+    #[test]
+    fn test_bam_header_section_round_trip() {
+        let text = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:1000\n\
+            @RG\tID:rg1\tPL:PACBIO\tSM:x\n@PG\tID:ccs\tPN:ccs\tVN:6.4\n";
+        let mut record = Record::new();
+        let cigar = CigarString::try_from("4M").unwrap();
+        record.set(b"read/1", Some(&cigar), b"ACGT", &[30; 4]);
+        record.set_tid(0);
+        record.set_pos(10);
+        record.push_aux(b"RG", Aux::String("rg1")).unwrap();
+        let batch = BatchReads(vec![ReadInfo::from_bam_record(
+            &record,
+            None,
+            &TagSelection::all(),
+        )]);
+
+        let storage = Arc::new(SeekStorage::new(Cursor::new(vec![])));
+        let (writer, sender) =
+            GasFileWriter::with_storage(storage.clone(), NonZero::new(1).unwrap());
+        writer.add_section(BAM_HEADER_SECTION, text.as_bytes().to_vec());
+        writer.start_write_worker();
+        sender.send(batch.to_gas_record(None)).unwrap();
+        drop(sender);
+        writer.wait_for_write_done();
+
+        let (reader, _recv) = GasFileReader::with_storage(storage, NonZero::new(1).unwrap());
+        let section = reader.section(BAM_HEADER_SECTION).unwrap();
+        assert_eq!(section, text.as_bytes());
+
+        // the reads go back to a bam with the read group and reference of the source
+        let out = NamedTempFile::with_suffix(".sam").unwrap();
+        let header = bam::Header::from_template(&bam::HeaderView::from_bytes(&section));
+        let mut bam_writer = bam::Writer::from_path(out.path(), &header, bam::Format::Sam).unwrap();
+        for (read, _encoded) in BatchReads::decode_iter(&reader.get(0).unwrap()) {
+            bam_writer.write(&read.to_record()).unwrap();
+        }
+        drop(bam_writer);
+
+        let mut bam_reader = bam::Reader::from_path(out.path()).unwrap();
+        let header_text = String::from_utf8(bam_reader.header().as_bytes().to_vec()).unwrap();
+        for line in text.lines() {
+            assert!(
+                header_text.contains(line),
+                "{} not in {}",
+                line,
+                header_text
+            );
+        }
+        let back = bam_reader.records().next().unwrap().unwrap();
+        assert_eq!((back.tid(), back.pos()), (0, 10));
+        assert_eq!(back.aux(b"RG").unwrap(), Aux::String("rg1"));
+    }
+
+    #[test]
+    fn test_decode_iter_bytes() {
+        let read = |name: &str, dw: u8| {
+            let mut record = Record::new();
+            record.set(name.as_bytes(), None, b"ACGTNacgt", &[30; 9]);
+            record.set_flags(4);
+            record.set_tid(-1);
+            record.set_pos(-1);
+            record.set_mtid(-1);
+            record.set_mpos(-1);
+            record.push_aux(b"rq", Aux::Float(0.99)).unwrap();
+            record
+                .push_aux(b"dw", Aux::ArrayU8((&[dw; 9][..]).into()))
+                .unwrap();
+            ReadInfo::from_bam_record(&record, None, &TagSelection::all())
+        };
+        let reads = || vec![read("a", 1), read("b", 2), read("c", 3)];
+        let cfg = get_bincode_cfg();
+        let batch = bincode::encode_to_vec(BatchReads(reads()), cfg).unwrap();
+        let decoded = BatchReads::decode_iter(&batch).collect::<Vec<_>>();
+        assert_eq!(decoded.len(), 3);
+        for ((decoded, encoded), read) in decoded.iter().zip(reads()) {
+            assert_eq!(decoded.name, read.name);
+            assert_eq!(*encoded, bincode::encode_to_vec(read, cfg).unwrap());
+        }
+
+        // gas-diff compares these bytes: a read is byte identical whatever batch it is in,
+        // and a changed tag changes them
+        let rebatched = bincode::encode_to_vec(BatchReads(reads().split_off(1)), cfg).unwrap();
+        let (_read, encoded) = BatchReads::decode_iter(&rebatched).next().unwrap();
+        assert_eq!(encoded, decoded[1].1);
+        assert_ne!(
+            bincode::encode_to_vec(read("b", 9), cfg).unwrap(),
+            decoded[1].1
+        );
+    }
+
+    #[test]
+    fn test_read_info_json() {
+        let mut record = Record::new();
+        record.set(b"m1/7/ccs", None, b"ACGTNACGT", &[30; 9]);
+        record.set_flags(4);
+        record.set_tid(-1);
+        record.set_pos(-1);
+        record.set_mtid(-1);
+        record.set_mpos(-1);
+        record.push_aux(b"np", Aux::U32(12)).unwrap();
+        record.push_aux(b"RG", Aux::String("rg1")).unwrap();
+        let read = ReadInfo::from_bam_record(&record, None, &TagSelection::all());
+
+        // gas-dump prints this value per read, a field is selected by its name
+        let value = serde_json::to_value(&read).unwrap();
+        assert_eq!(value["name"], "m1/7/ccs");
+        assert_eq!(value["seq"], "ACGTNACGT");
+        assert_eq!(value["np"], 12);
+        assert_eq!(value["qual"].as_array().map(Vec::len), Some(9));
+        assert_eq!(value["aln"], serde_json::Value::Null);
+        assert_eq!(
+            value["tags"],
+            serde_json::json!([{"tag": "RG", "value": {"String": "rg1"}}])
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::{fmt::Display, ops::Deref};
+
+use bincode::{
+    Decode, Encode,
+    de::{Decoder, read::Reader},
+    enc::{Encoder, write::Writer},
+    error::{DecodeError, EncodeError},
+};
+use serde::Serialize;
+
+const BASES: [u8; 4] = *b"ACGT";
+
+fn base_code(base: u8) -> Option<u8> {
+    match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+/// the bases of a read. in memory it is the text, bincode packs A/C/G/T into 2 bits.
+/// lowercase (soft-masked) bases are packed as uppercase plus a run mask, any other byte
+/// (N, IUPAC codes) goes to run-length exceptions, so every text round trips and N gaps stay small.
+/// encoded as: varint len | len/4 packed bytes | exception runs | lowercase runs.
+/// runs start at a distance from the end of the previous run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct PackedSeq(pub String);
+
+impl Deref for PackedSeq {
+    type Target = String;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<String> for PackedSeq {
+    fn from(seq: String) -> Self {
+        Self(seq)
+    }
+}
+
+impl Display for PackedSeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// (distance, run len, byte) of the uppercased bytes that are not A/C/G/T
+type ExceptionRuns = Vec<(usize, usize, u8)>;
+
+/// (distance, run len) of the lowercase bytes
+type LowercaseRuns = Vec<(usize, usize)>;
+
+impl Encode for PackedSeq {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        let bases = self.0.as_bytes();
+        let mut packed = vec![0_u8; bases.len().div_ceil(4)];
+        let mut exceptions: ExceptionRuns = vec![];
+        let mut lowercase: LowercaseRuns = vec![];
+        let (mut exception_end, mut lowercase_end) = (0, 0);
+        for (i, &base) in bases.iter().enumerate() {
+            if base.is_ascii_lowercase() {
+                match lowercase.last_mut() {
+                    Some((_, run_len)) if lowercase_end == i => *run_len += 1,
+                    _ => lowercase.push((i - lowercase_end, 1)),
+                }
+                lowercase_end = i + 1;
+            }
+            let base = base.to_ascii_uppercase();
+            match base_code(base) {
+                Some(code) => packed[i / 4] |= code << (i % 4 * 2),
+                None => {
+                    match exceptions.last_mut() {
+                        Some((_, run_len, byte)) if exception_end == i && *byte == base => {
+                            *run_len += 1
+                        }
+                        _ => exceptions.push((i - exception_end, 1, base)),
+                    }
+                    exception_end = i + 1;
+                }
+            }
+        }
+        bases.len().encode(encoder)?;
+        encoder.writer().write(&packed)?;
+        exceptions.encode(encoder)?;
+        lowercase.encode(encoder)
+    }
+}
+
+/// the range of a run, checked against the sequence length
+fn run_range(
+    end_of_previous: usize,
+    distance: usize,
+    run_len: usize,
+    len: usize,
+) -> Result<std::ops::Range<usize>, DecodeError> {
+    let start = end_of_previous.checked_add(distance);
+    let end = start.and_then(|start| start.checked_add(run_len));
+    match (start, end) {
+        (Some(start), Some(end)) if end <= len => Ok(start..end),
+        _ => Err(DecodeError::Other("seq run out of range")),
+    }
+}
+
+impl<Context> Decode<Context> for PackedSeq {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        let len = usize::decode(decoder)?;
+        decoder.claim_bytes_read(len.div_ceil(4))?;
+        let mut packed = vec![0_u8; len.div_ceil(4)];
+        decoder.reader().read(&mut packed)?;
+        let mut bases = (0..len)
+            .map(|i| BASES[(packed[i / 4] >> (i % 4 * 2) & 0b11) as usize])
+            .collect::<Vec<_>>();
+
+        let exceptions: ExceptionRuns = Decode::decode(decoder)?;
+        let mut end = 0;
+        for (distance, run_len, byte) in exceptions {
+            let range = run_range(end, distance, run_len, len)?;
+            end = range.end;
+            bases[range].fill(byte);
+        }
+        let lowercase: LowercaseRuns = Decode::decode(decoder)?;
+        let mut end = 0;
+        for (distance, run_len) in lowercase {
+            let range = run_range(end, distance, run_len, len)?;
+            end = range.end;
+            bases[range].make_ascii_lowercase();
+        }
+        String::from_utf8(bases)
+            .map(Self)
+            .map_err(|err| DecodeError::Utf8 {
+                inner: err.utf8_error(),
+            })
+    }
+}
+
+bincode::impl_borrow_decode!(PackedSeq);
+
+#[cfg(test)]
+mod test {
+    use crate::io::v1::get_bincode_cfg;
+
+    use super::PackedSeq;
+
+    #[test]
+    fn test_packed_seq() {
+        let cfg = get_bincode_cfg();
+        for seq in [
+            "",
+            "A",
+            "ACGTT",
+            "NACGTRYN",
+            "acgtACGT",
+            "ACGTNNNNACG",
+            "nnNNacRyN",
+            "AC\u{e9}GT",
+        ] {
+            let seq = PackedSeq(seq.to_string());
+            let data = bincode::encode_to_vec(&seq, cfg).unwrap();
+            let (decoded, nbytes): (PackedSeq, usize) =
+                bincode::decode_from_slice(&data, cfg).unwrap();
+            assert_eq!((decoded, nbytes), (seq, data.len()));
+        }
+
+        let hifi = PackedSeq("ACGT".repeat(5_000));
+        let data = bincode::encode_to_vec(&hifi, cfg).unwrap();
+        assert!(data.len() < hifi.len() / 4 + 8, "{} bytes", data.len());
+
+        // an N gap and a soft-masked stretch cost a few bytes each, not one entry per base
+        let gapped = PackedSeq(format!(
+            "{}{}{}{}",
+            "ACGT".repeat(1_000),
+            "N".repeat(5_000),
+            "acgt".repeat(1_000),
+            "ACGT".repeat(1_000)
+        ));
+        let data = bincode::encode_to_vec(&gapped, cfg).unwrap();
+        assert!(data.len() < gapped.len() / 4 + 24, "{} bytes", data.len());
+        let (decoded, _nbytes): (PackedSeq, usize) =
+            bincode::decode_from_slice(&data, cfg).unwrap();
+        assert_eq!(decoded, gapped);
+    }
+}